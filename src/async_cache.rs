@@ -0,0 +1,326 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, MutexGuard, PoisonError};
+
+use tokio::sync::watch;
+
+use crate::{flight, CacheError, Codec, FnCodec, LocalFileCache, Shared, TryInsertError};
+
+/// Async front end of a [`LocalFileCache`] for the tokio runtime.
+///
+/// File I/O runs on the blocking thread pool and producers are futures. Concurrent misses of the same key are
+/// coalesced across tasks, threads and processes like in [`LocalFileCache::or_insert_with`]. Waiting for another
+/// producer is bounded by the lock timeout, which needs the time driver of the runtime to be enabled.
+pub struct AsyncLocalFileCache<T, C = FnCodec<T>> {
+    inner: Arc<LocalFileCache<T, C>>,
+    flights: AsyncKeyLocks<Shared<T>>,
+}
+
+impl<T, C> AsyncLocalFileCache<T, C>
+    where T: Send + Sync + 'static, C: Codec<T> + Send + Sync + 'static
+{
+    pub fn new(cache: LocalFileCache<T, C>) -> Self {
+        Self { inner: Arc::new(cache), flights: AsyncKeyLocks::default() }
+    }
+
+    /// Returns the underlying cache for blocking use.
+    pub fn blocking(&self) -> &LocalFileCache<T, C> {
+        &self.inner
+    }
+
+    /// Returns the cached value, or awaits `f` and caches its result on a miss.
+    pub async fn or_insert_with<K, F, Fut>(&self, k: K, f: F) -> Result<T, CacheError>
+        where K: AsRef<Path>, F: FnOnce() -> Fut, Fut: Future<Output = T>
+    {
+        self.try_or_insert_with(k, || async { Ok::<T, std::convert::Infallible>(f().await) }).await
+            .map_err(|e| match e {
+                TryInsertError::Cache(e) => e,
+                TryInsertError::Producer(e) => match e {},
+            })
+    }
+
+    /// Same as [`Self::or_insert_with`] but the producer may fail.
+    /// An `Err` from the producer is returned as [`TryInsertError::Producer`] and is never cached.
+    pub async fn try_or_insert_with<K, F, Fut, E>(&self, k: K, f: F) -> Result<T, TryInsertError<E>>
+        where K: AsRef<Path>, F: FnOnce() -> Fut, Fut: Future<Output = Result<T, E>>
+    {
+        let k = k.as_ref().to_owned();
+        let path = self.inner.entry_path(&k)?;
+        if let Some(v) = self.load(&path, &k).await? {
+            return Ok(v);
+        }
+
+        // Tasks wait here without blocking a thread and get the value of the leading task. The leader also takes the
+        // locks of the blocking API.
+        let timeout = self.inner.lock_timeout;
+        let joined = tokio::time::timeout(timeout, self.flights.join(&path)).await
+            .map_err(|_| CacheError::LockTimeout { path: path.clone(), timeout })?;
+        let mut task_guard = match joined {
+            AsyncJoined::Leader(guard) => guard,
+            AsyncJoined::Waited(Some(shared)) => {
+                let inner = self.inner.clone();
+                return Ok(blocking(move || inner.codec.decode(&shared.bytes)).await.map_err(CacheError::Decode)?);
+            },
+            // Nothing was cached, so the waiting tasks run their own producers at the same time.
+            AsyncJoined::Waited(None) => {
+                if let Some(v) = self.load(&path, &k).await? {
+                    return Ok(v);
+                }
+                let v = f().await.map_err(TryInsertError::Producer)?;
+                let (guard, file_lock) = self.lock(&path).await?;
+                return Ok(self.store(path, k, v, guard, file_lock).await?.0);
+            },
+        };
+        let (guard, file_lock) = self.lock(&path).await?;
+        if let Some(v) = self.load(&path, &k).await? {
+            return Ok(v);
+        }
+
+        let v = f().await.map_err(TryInsertError::Producer)?;
+        let (v, shared) = self.store(path, k, v, guard, file_lock).await?;
+        if let Some(shared) = shared {
+            task_guard.publish(shared);
+        }
+        Ok(v)
+    }
+
+    /// See [`LocalFileCache::get`].
+    pub async fn get<K: AsRef<Path>>(&self, k: K) -> Result<Option<T>, CacheError> {
+        let k = k.as_ref().to_owned();
+        self.load(&self.inner.entry_path(&k)?, &k).await
+    }
+
+    /// See [`LocalFileCache::insert`]. The value is consumed because it is encoded on the blocking thread pool.
+    pub async fn insert<K: AsRef<Path>>(&self, k: K, v: T) -> Result<(), CacheError> {
+        let (inner, k) = (self.inner.clone(), k.as_ref().to_owned());
+        blocking(move || inner.insert(k, &v)).await
+    }
+
+    /// See [`LocalFileCache::remove`].
+    pub async fn remove<K: AsRef<Path>>(&self, k: K) -> Result<bool, CacheError> {
+        let (inner, k) = (self.inner.clone(), k.as_ref().to_owned());
+        blocking(move || inner.remove(k)).await
+    }
+
+    /// See [`LocalFileCache::contains`].
+    pub async fn contains<K: AsRef<Path>>(&self, k: K) -> Result<bool, CacheError> {
+        let (inner, k) = (self.inner.clone(), k.as_ref().to_owned());
+        blocking(move || inner.contains(k)).await
+    }
+
+    /// See [`LocalFileCache::flush`].
+    pub async fn flush(&self) -> Result<(), CacheError> {
+        let inner = self.inner.clone();
+        blocking(move || inner.flush()).await
+    }
+
+    // Takes the locks of the blocking API.
+    async fn lock(&self, path: &Path) -> Result<(flight::KeyGuard<Shared<T>>, Option<flight::FileLock>), CacheError> {
+        let (inner, path) = (self.inner.clone(), path.to_owned());
+        blocking(move || inner.lock(&path)).await
+    }
+
+    // Writes the entry while the locks are held and hands it to the threads waiting for them. Returns the value and
+    // what was stored.
+    async fn store(
+        &self, path: PathBuf, k: PathBuf, v: T, mut guard: flight::KeyGuard<Shared<T>>, file_lock: Option<flight::FileLock>,
+    ) -> Result<(T, Option<Shared<T>>), CacheError> {
+        let inner = self.inner.clone();
+        let (v, shared) = blocking(move || {
+            let shared = inner.store(&path, &k, &v, None, file_lock.is_some())
+                .map(|stored| stored.map(|stored| Shared { bytes: stored.bytes, value: None }));
+            if let Ok(Some(shared)) = &shared {
+                guard.publish(shared.clone());
+            }
+            drop((guard, file_lock));
+            (v, shared)
+        }).await;
+        Ok((v, shared?))
+    }
+
+    async fn load(&self, path: &Path, k: &Path) -> Result<Option<T>, CacheError> {
+        let (inner, path, k) = (self.inner.clone(), path.to_owned(), k.to_owned());
+        blocking(move || inner.load(&path, &k)).await
+    }
+}
+
+impl<T, C> From<LocalFileCache<T, C>> for AsyncLocalFileCache<T, C>
+    where T: Send + Sync + 'static, C: Codec<T> + Send + Sync + 'static
+{
+    fn from(cache: LocalFileCache<T, C>) -> Self {
+        Self::new(cache)
+    }
+}
+
+// Runs f on the blocking thread pool. Panics are propagated to the caller.
+async fn blocking<R, F>(f: F) -> R
+    where R: Send + 'static, F: FnOnce() -> R + Send + 'static
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(r) => r,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+// Per-key flights of tasks, see flight::KeyLocks. Entries are removed once the leader is done.
+struct AsyncKeyLocks<V> {
+    // Receives the outcome of the leader: None while it runs, then what it published.
+    flights: std::sync::Mutex<HashMap<PathBuf, watch::Receiver<Option<Option<V>>>>>,
+}
+
+enum AsyncJoined<'a, V> {
+    Leader(AsyncKeyGuard<'a, V>),
+    // What the leader published, None if it had nothing to share.
+    Waited(Option<V>),
+}
+
+impl<V> Default for AsyncKeyLocks<V> {
+    fn default() -> Self {
+        Self { flights: std::sync::Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<V: Clone> AsyncKeyLocks<V> {
+    // Leads the path if no other task does, otherwise waits for the leader to finish.
+    async fn join(&self, path: &Path) -> AsyncJoined<'_, V> {
+        let mut outcome = match self.flights().entry(path.to_owned()) {
+            Entry::Occupied(e) => e.get().clone(),
+            Entry::Vacant(e) => {
+                let (sender, receiver) = watch::channel(None);
+                e.insert(receiver);
+                return AsyncJoined::Leader(AsyncKeyGuard { locks: self, path: path.to_owned(), sender, published: None });
+            },
+        };
+        // The guard sends the outcome before it drops the sender.
+        let published = outcome.wait_for(Option::is_some).await.ok().and_then(|published| published.clone().flatten());
+        AsyncJoined::Waited(published)
+    }
+}
+
+impl<V> AsyncKeyLocks<V> {
+    fn flights(&self) -> MutexGuard<'_, HashMap<PathBuf, watch::Receiver<Option<Option<V>>>>> {
+        self.flights.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+struct AsyncKeyGuard<'a, V> {
+    locks: &'a AsyncKeyLocks<V>,
+    path: PathBuf,
+    sender: watch::Sender<Option<Option<V>>>,
+    published: Option<V>,
+}
+
+impl<V> AsyncKeyGuard<'_, V> {
+    fn publish(&mut self, v: V) {
+        self.published = Some(v);
+    }
+}
+
+impl<V> Drop for AsyncKeyGuard<'_, V> {
+    fn drop(&mut self) {
+        self.locks.flights().remove(&self.path);
+        self.sender.send_replace(Some(self.published.take()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{string_cache, string_codec};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_tasks_call_the_producer_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(AsyncLocalFileCache::new(
+            string_cache(dir.path()).build(string_codec()).unwrap()
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..8).map(|_| {
+            let (cache, calls) = (cache.clone(), calls.clone());
+            tokio::spawn(async move {
+                cache.or_insert_with("data0", || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    "abc".to_owned()
+                }).await.unwrap()
+            })
+        }).collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "abc");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(cache.flights.flights().is_empty());
+
+        assert_eq!(cache.get("data0").await.unwrap(), Some("abc".to_owned()));
+        assert!(cache.remove("data0").await.unwrap());
+        assert!(!cache.contains("data0").await.unwrap());
+        let err = cache.try_or_insert_with("data0", || async { Err("offline") }).await.unwrap_err();
+        assert!(matches!(err, TryInsertError::Producer("offline")));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn waiting_tasks_share_the_result_of_the_leader() {
+        let dir = tempfile::tempdir().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let race = |cache: AsyncLocalFileCache<String>| {
+            let (cache, calls) = (Arc::new(cache), calls.clone());
+            async move {
+                let started = Instant::now();
+                let tasks: Vec<_> = (0..8).map(|_| {
+                    let (cache, calls) = (cache.clone(), calls.clone());
+                    tokio::spawn(async move {
+                        cache.or_insert_with("data0", || async {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            "abc".to_owned()
+                        }).await.unwrap()
+                    })
+                }).collect();
+                for task in tasks {
+                    assert_eq!(task.await.unwrap(), "abc");
+                }
+                (calls.swap(0, Ordering::SeqCst), started.elapsed())
+            }
+        };
+
+        // The entry expires right away, so it cannot be read back.
+        let expiring = string_cache(dir.path()).ttl(Duration::ZERO).build(string_codec()).unwrap();
+        assert_eq!(race(AsyncLocalFileCache::new(expiring)).await.0, 1);
+
+        // Waiting tasks do not run their producers one after another when nothing was cached.
+        let uncacheable = string_cache(dir.path()).build_with::<String>(
+            Box::new(|_| None),
+            Box::new(|data| Ok(String::from_utf8(data.to_vec())?)),
+        ).unwrap();
+        let (calls, elapsed) = race(AsyncLocalFileCache::new(uncacheable)).await;
+        assert_eq!(calls, 8);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn waits_are_bounded_by_the_lock_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = AsyncLocalFileCache::new(
+            string_cache(dir.path()).lock_timeout(Duration::from_millis(20)).build(string_codec()).unwrap()
+        );
+        let path = cache.inner.entry_path("data0").unwrap();
+
+        // Held by another task.
+        let AsyncJoined::Leader(task_guard) = cache.flights.join(&path).await else { panic!() };
+        let err = cache.or_insert_with("data0", || async { "abc".to_owned() }).await.unwrap_err();
+        assert!(matches!(err, CacheError::LockTimeout { .. }));
+        drop(task_guard);
+
+        // Held by a thread using the blocking API, even where files cannot be locked.
+        let thread_guard = cache.blocking().flights.lock(&path, Duration::ZERO).unwrap();
+        let err = cache.or_insert_with("data0", || async { "abc".to_owned() }).await.unwrap_err();
+        assert!(matches!(err, CacheError::LockTimeout { .. }));
+        drop(thread_guard);
+
+        assert_eq!(cache.or_insert_with("data0", || async { "abc".to_owned() }).await.unwrap(), "abc");
+    }
+}
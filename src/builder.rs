@@ -0,0 +1,226 @@
+use std::env;
+use std::ffi::OsString;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{
+    CacheError, Checksum, Codec, Compression, CorruptionHook, Durability, EvictionPolicy, FnCodec, FromU8, KeyMode, LocalFileCache,
+    ToU8, eviction,
+};
+use crate::memory::MemoryTier;
+
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Configures and creates a [`LocalFileCache`].
+///
+/// The cache directory is `<base>/<sub_path>` where the base is the first available of:
+/// 1. the directory given by [`Self::base_dir`],
+/// 2. the value of the environment variable given by [`Self::env_var`],
+/// 3. the platform cache directory (`$XDG_CACHE_HOME` or `~/.cache` on Linux),
+/// 4. the system temp directory, unless disabled by [`Self::temp_dir_fallback`].
+pub struct LocalFileCacheBuilder {
+    sub_path: PathBuf,
+    base_dir: Option<PathBuf>,
+    env_var: Option<OsString>,
+    temp_dir_fallback: bool,
+    ttl: Option<Duration>,
+    max_bytes: Option<u64>,
+    max_entries: Option<usize>,
+    eviction_policy: Option<Box<dyn EvictionPolicy>>,
+    key_mode: KeyMode,
+    compression: Compression,
+    checksum: Checksum,
+    schema_version: u32,
+    durability: Durability,
+    lock_timeout: Duration,
+    memory_capacity: Option<usize>,
+    on_corrupt: Option<CorruptionHook>,
+}
+
+impl LocalFileCacheBuilder {
+    pub fn new<P: AsRef<Path>>(sub_path: P) -> Self {
+        Self {
+            sub_path: sub_path.as_ref().to_owned(),
+            base_dir: None,
+            env_var: None,
+            temp_dir_fallback: true,
+            ttl: None,
+            max_bytes: None,
+            max_entries: None,
+            eviction_policy: None,
+            key_mode: KeyMode::Path,
+            compression: Compression::None,
+            checksum: Checksum::default(),
+            schema_version: 0,
+            durability: Durability::default(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            memory_capacity: None,
+            on_corrupt: None,
+        }
+    }
+
+    /// Uses the given base directory instead of the platform cache directory.
+    pub fn base_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.base_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Uses the value of the environment variable as the base directory if it is set and not empty.
+    pub fn env_var<S: Into<OsString>>(mut self, name: S) -> Self {
+        self.env_var = Some(name.into());
+        self
+    }
+
+    /// Whether to fall back to the system temp directory when no other base directory is available. Defaults to true.
+    pub fn temp_dir_fallback(mut self, enabled: bool) -> Self {
+        self.temp_dir_fallback = enabled;
+        self
+    }
+
+    /// See [`LocalFileCache::with_ttl`].
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// See [`LocalFileCache::with_max_bytes`].
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// See [`LocalFileCache::with_max_entries`].
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// See [`LocalFileCache::with_eviction_policy`].
+    pub fn eviction_policy<P: EvictionPolicy + 'static>(mut self, policy: P) -> Self {
+        self.eviction_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// See [`LocalFileCache::with_key_mode`].
+    pub fn key_mode(mut self, key_mode: KeyMode) -> Self {
+        self.key_mode = key_mode;
+        self
+    }
+
+    /// See [`LocalFileCache::with_compression`].
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// See [`LocalFileCache::with_checksum`].
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// See [`LocalFileCache::with_schema_version`].
+    pub fn schema_version(mut self, schema_version: u32) -> Self {
+        self.schema_version = schema_version;
+        self
+    }
+
+    /// See [`LocalFileCache::with_durability`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// See [`LocalFileCache::with_lock_timeout`].
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// See [`LocalFileCache::with_memory_capacity`]. The total weight is limited on the cache built, see
+    /// [`LocalFileCache::with_memory_weight`].
+    pub fn memory_capacity(mut self, max_entries: usize) -> Self {
+        self.memory_capacity = Some(max_entries);
+        self
+    }
+
+    /// See [`LocalFileCache::on_corrupt`].
+    pub fn on_corrupt<H>(mut self, hook: H) -> Self
+        where H: Fn(&Path, &CacheError) + Send + Sync + 'static
+    {
+        self.on_corrupt = Some(Box::new(hook));
+        self
+    }
+
+    /// Returns the directory the cache will use.
+    pub fn dir(&self) -> Result<PathBuf, CacheError> {
+        let from_env = self.env_var.as_ref()
+            .and_then(env::var_os)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from);
+        let mut dir = self.base_dir.clone()
+            .or(from_env)
+            .or_else(dirs::cache_dir)
+            .or_else(|| self.temp_dir_fallback.then(env::temp_dir))
+            .ok_or(CacheError::NoCacheDir)?;
+        dir.push(&self.sub_path);
+        Ok(dir)
+    }
+
+    pub fn build<T, C: Codec<T>>(self, codec: C) -> Result<LocalFileCache<T, C>, CacheError> {
+        Ok(LocalFileCache {
+            dir: self.dir()?,
+            codec,
+            value: PhantomData,
+            on_corrupt: self.on_corrupt,
+            ttl: self.ttl,
+            max_bytes: self.max_bytes,
+            max_entries: self.max_entries,
+            eviction_policy: self.eviction_policy.unwrap_or_else(|| Box::new(eviction::Lru)),
+            key_mode: self.key_mode,
+            compression: self.compression,
+            checksum: self.checksum,
+            schema_version: self.schema_version,
+            durability: self.durability,
+            lock_timeout: self.lock_timeout,
+            flights: Default::default(),
+            memory: self.memory_capacity.map(|max_entries| {
+                let mut memory = MemoryTier::new();
+                memory.set_max_entries(max_entries);
+                memory
+            }),
+        })
+    }
+
+    /// Builds a cache that encodes values with the given closures. See [`FnCodec`].
+    pub fn build_with<T>(self, to_u8: ToU8<T>, from_u8: FromU8<T>) -> Result<LocalFileCache<T>, CacheError> {
+        self.build(FnCodec::new(to_u8, from_u8))
+    }
+
+    /// Builds a cache that stores values in the given serde format.
+    #[cfg(feature = "serde")]
+    pub fn build_serde<T, F>(self) -> Result<LocalFileCache<T, crate::formats::SerdeCodec<F>>, CacheError>
+        where T: serde::Serialize + serde::de::DeserializeOwned, F: crate::formats::SerdeFormat
+    {
+        self.build(crate::formats::SerdeCodec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_base_dir_wins() {
+        let builder = LocalFileCacheBuilder::new("app").base_dir("/base").env_var("LOCAL_FILE_CACHE_TEST_UNUSED");
+        assert_eq!(builder.dir().unwrap(), PathBuf::from("/base/app"));
+    }
+
+    #[test]
+    fn unset_env_var_is_skipped() {
+        let dir = LocalFileCacheBuilder::new("app").env_var("LOCAL_FILE_CACHE_TEST_UNSET").dir().unwrap();
+        let expected = dirs::cache_dir().unwrap_or_else(env::temp_dir).join("app");
+        assert_eq!(dir, expected);
+    }
+}
@@ -0,0 +1,95 @@
+use sha2::{Digest, Sha256};
+
+/// Checksum written with every new entry and verified on read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Checksum {
+    None,
+    /// CRC-32. Cheap, detects bit rot and truncation.
+    #[default]
+    Crc32,
+    /// SHA-256.
+    Sha256,
+}
+
+pub(crate) const CHECKSUM_LEN: usize = 32;
+
+const NONE: u8 = 0;
+const CRC32: u8 = 1;
+const SHA256: u8 = 2;
+
+impl Checksum {
+    // Id recorded in the entry header.
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Checksum::None => NONE,
+            Checksum::Crc32 => CRC32,
+            Checksum::Sha256 => SHA256,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            NONE => Some(Checksum::None),
+            CRC32 => Some(Checksum::Crc32),
+            SHA256 => Some(Checksum::Sha256),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn hasher(&self) -> Hasher {
+        match self {
+            Checksum::None => Hasher::None,
+            Checksum::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            Checksum::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub(crate) fn compute(&self, data: &[u8]) -> [u8; CHECKSUM_LEN] {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+}
+
+pub(crate) enum Hasher {
+    None,
+    Crc32(crc32fast::Hasher),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::None => {},
+            Hasher::Crc32(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    // Digests shorter than CHECKSUM_LEN are zero padded.
+    pub fn finish(self) -> [u8; CHECKSUM_LEN] {
+        let mut out = [0u8; CHECKSUM_LEN];
+        match self {
+            Hasher::None => {},
+            Hasher::Crc32(h) => out[..4].copy_from_slice(&h.finalize().to_le_bytes()),
+            Hasher::Sha256(h) => out.copy_from_slice(&h.finalize()),
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_detect_changes() {
+        for checksum in [Checksum::Crc32, Checksum::Sha256] {
+            assert_eq!(Checksum::from_id(checksum.id()), Some(checksum));
+            assert_eq!(checksum.compute(b"abc"), checksum.compute(b"abc"));
+            assert_ne!(checksum.compute(b"abc"), checksum.compute(b"abd"));
+        }
+        assert_eq!(Checksum::None.compute(b"abc"), [0u8; CHECKSUM_LEN]);
+        assert_eq!(Checksum::from_id(255), None);
+    }
+}
@@ -0,0 +1,106 @@
+//! Codecs convert cached values to and from bytes.
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use crate::{FromU8, ToU8};
+
+/// Error returned by a [`Codec`].
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+// Codec id of the raw entries written by `or_insert_with_stream`.
+pub(crate) const STREAM_ID: u32 = 255;
+
+/// Converts values of type `T` to and from the payload of cache entries.
+pub trait Codec<T> {
+    /// Identifies the encoding. It is recorded in every entry and entries written with another id are treated as misses.
+    /// Ids below 256 are reserved for the codecs of this crate.
+    fn id(&self) -> u32;
+
+    /// Writes the encoded value. Return [`Uncacheable`] to skip caching the value.
+    fn encode(&self, value: &T, w: &mut dyn Write) -> Result<(), CodecError>;
+
+    /// Decodes a value. An error marks the entry as corrupt.
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError>;
+
+    /// Decodes a value from a reader.
+    fn decode_from(&self, r: &mut dyn Read) -> Result<T, CodecError> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        self.decode(&bytes)
+    }
+}
+
+/// Error returned by [`Codec::encode`] when the value should not be cached.
+/// The value is still returned to the caller of `or_insert_with`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Uncacheable;
+
+impl fmt::Display for Uncacheable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "value is not cacheable")
+    }
+}
+
+impl std::error::Error for Uncacheable {}
+
+/// Adapts a pair of `to_u8`/`from_u8` closures to [`Codec`]. `to_u8` returning `None` means [`Uncacheable`].
+pub struct FnCodec<T> {
+    id: u32,
+    to_u8: ToU8<T>,
+    from_u8: FromU8<T>,
+}
+
+impl<T> FnCodec<T> {
+    /// Creates a codec with id 0.
+    pub fn new(to_u8: ToU8<T>, from_u8: FromU8<T>) -> Self {
+        Self { id: 0, to_u8, from_u8 }
+    }
+
+    /// Sets the codec id. Change it when the closures change the encoding.
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = id;
+        self
+    }
+}
+
+impl<T> Codec<T> for FnCodec<T> {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn encode(&self, value: &T, w: &mut dyn Write) -> Result<(), CodecError> {
+        match (self.to_u8)(value) {
+            Some(bin) => Ok(w.write_all(&bin)?),
+            None => Err(Box::new(Uncacheable)),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        (self.from_u8)(bytes)
+    }
+}
+
+pub(crate) fn is_uncacheable(e: &CodecError) -> bool {
+    e.downcast_ref::<Uncacheable>().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fn_codec_reports_uncacheable() {
+        let codec = FnCodec::<u8>::new(
+            Box::new(|v| if *v == 0 { None } else { Some(vec![*v]) }),
+            Box::new(|bin| Ok(bin[0])),
+        ).with_id(300);
+        assert_eq!(codec.id(), 300);
+
+        let mut bin = Vec::new();
+        codec.encode(&7, &mut bin).unwrap();
+        assert_eq!(codec.decode_from(&mut bin.as_slice()).unwrap(), 7);
+
+        assert!(is_uncacheable(&codec.encode(&0, &mut Vec::new()).unwrap_err()));
+    }
+}
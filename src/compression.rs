@@ -0,0 +1,113 @@
+use std::borrow::Cow;
+use std::io;
+
+use crate::codec::CodecError;
+
+/// Compression applied to the payload of new entries.
+/// The algorithm is recorded per entry, so entries written with other settings stay readable.
+/// Variants depend on the enabled features, so matches need a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Compression {
+    #[default]
+    None,
+    /// Zstandard with the given level (1-22, 0 selects the library default).
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+    /// Gzip with the given level (0-9).
+    #[cfg(feature = "gzip")]
+    Gzip { level: u32 },
+}
+
+const NONE: u8 = 0;
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 1;
+#[cfg(feature = "gzip")]
+const GZIP: u8 = 2;
+
+impl Compression {
+    // Id recorded in the entry header.
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Compression::None => NONE,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { .. } => ZSTD,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip { .. } => GZIP,
+        }
+    }
+
+    pub(crate) fn compress_into(&self, payload: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Compression::None => {
+                out.extend_from_slice(payload);
+                Ok(())
+            },
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => zstd::stream::copy_encode(payload, out, *level),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip { level } => {
+                use std::io::Write;
+                let mut encoder = flate2::write::GzEncoder::new(out, flate2::Compression::new(*level));
+                encoder.write_all(payload)?;
+                encoder.finish().map(|_| ())
+            },
+        }
+    }
+}
+
+// False for compression ids written by a build with other features enabled.
+pub(crate) fn is_supported(id: u8) -> bool {
+    match id {
+        NONE => true,
+        #[cfg(feature = "zstd")]
+        ZSTD => true,
+        #[cfg(feature = "gzip")]
+        GZIP => true,
+        _ => false,
+    }
+}
+
+pub(crate) fn decompress(id: u8, payload: &[u8]) -> Result<Cow<'_, [u8]>, CodecError> {
+    match id {
+        NONE => Ok(Cow::Borrowed(payload)),
+        #[cfg(feature = "zstd")]
+        ZSTD => Ok(Cow::Owned(zstd::stream::decode_all(payload)?)),
+        #[cfg(feature = "gzip")]
+        GZIP => {
+            use std::io::Read;
+            let mut out = Vec::new();
+            flate2::read::GzDecoder::new(payload).read_to_end(&mut out)?;
+            Ok(Cow::Owned(out))
+        },
+        _ => Err(format!("unsupported compression {}", id).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(compression: Compression) {
+        let payload = b"0123456789".repeat(100);
+        let mut out = Vec::new();
+        compression.compress_into(&payload, &mut out).unwrap();
+        if compression != Compression::None {
+            assert!(out.len() < payload.len());
+        }
+        assert_eq!(decompress(compression.id(), &out).unwrap().as_ref(), payload.as_slice());
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        round_trip(Compression::None);
+        #[cfg(feature = "zstd")]
+        round_trip(Compression::Zstd { level: 3 });
+        #[cfg(feature = "gzip")]
+        round_trip(Compression::Gzip { level: 6 });
+
+        assert!(is_supported(Compression::None.id()));
+        assert!(!is_supported(255));
+        assert!(decompress(255, b"").is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// How far new entries are flushed to stable storage before they become visible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Nothing is synced. After a power loss an entry may be truncated or empty; such entries fail their
+    /// checksum and are regenerated.
+    None,
+    /// The entry file is synced before it is renamed into place.
+    File,
+    /// The entry file is synced before the rename and its directory after it, so the entry survives a power loss
+    /// once the insert returned.
+    #[default]
+    FileAndDir,
+}
+
+impl Durability {
+    pub(crate) fn sync_file(&self, f: &File) -> io::Result<()> {
+        match self {
+            Durability::None => Ok(()),
+            Durability::File | Durability::FileAndDir => f.sync_all(),
+        }
+    }
+
+    pub(crate) fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        match self {
+            Durability::FileAndDir => sync_dir(dir),
+            Durability::None | Durability::File => Ok(()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// Directories cannot be opened for syncing on other platforms, so only the file is synced.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn every_level_syncs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = File::create(dir.path().join("entry")).unwrap();
+        f.write_all(b"abc").unwrap();
+        for durability in [Durability::None, Durability::File, Durability::FileAndDir] {
+            durability.sync_file(&f).unwrap();
+            durability.sync_dir(dir.path()).unwrap();
+        }
+    }
+}
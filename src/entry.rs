@@ -0,0 +1,301 @@
+//! On-disk layout of cache entries.
+//!
+//! Every entry file consists of a header followed by the payload, which is the value as encoded by the
+//! [`Codec`](crate::Codec), optionally compressed. All integers are little endian and all times are
+//! milliseconds since the unix epoch.
+//!
+//! | Offset    | Size | Field                                                                |
+//! |-----------|------|----------------------------------------------------------------------|
+//! | 0         | 4    | magic, [`MAGIC`]                                                     |
+//! | 4         | 2    | format version, [`FORMAT_VERSION`]                                   |
+//! | 6         | 2    | flags, none are defined yet so this must be 0                        |
+//! | 8         | 8    | created at                                                           |
+//! | 16        | 8    | expires at, 0 means never                                            |
+//! | 24        | 8    | last access, updated in place on every hit                           |
+//! | 32        | 8    | hit count, updated in place on every hit                             |
+//! | 40        | 8    | score (signed), updated in place by `set_score`                      |
+//! | 48        | 4    | codec id, see [`Codec::id`](crate::Codec::id), 255 for raw streams   |
+//! | 52        | 1    | compression: 0 none, 1 zstd, 2 gzip                                  |
+//! | 53        | 1    | checksum algorithm: 0 none, 1 CRC-32, 2 SHA-256                      |
+//! | 54        | 2    | reserved, 0                                                          |
+//! | 56        | 4    | application schema version                                           |
+//! | 60        | 32   | checksum of the payload as stored, zero padded                       |
+//! | 92        | 4    | key length `n`                                                       |
+//! | 96        | `n`  | original key                                                         |
+//! | 96 + `n`  |      | payload                                                              |
+//!
+//! Entries with another magic or format version are treated as misses and are rewritten.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::checksum::CHECKSUM_LEN;
+use crate::CacheError;
+
+/// Magic bytes at the start of every entry file.
+pub const MAGIC: [u8; 4] = *b"LFCE";
+/// Version of the layout described in this module.
+pub const FORMAT_VERSION: u16 = 2;
+
+// Length of the fixed part of the header.
+pub(crate) const HEADER_LEN: usize = 96;
+const LAST_ACCESS_OFFSET: u64 = 24;
+const SCORE_OFFSET: u64 = 40;
+const CHECKSUM_OFFSET: usize = 60;
+const KEY_LEN_OFFSET: usize = 92;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub created_at: SystemTime,
+    pub expires_at: Option<SystemTime>,
+    pub last_access: SystemTime,
+    pub hits: u64,
+    pub score: i64,
+    pub codec_id: u32,
+    pub compression: u8,
+    pub checksum_kind: u8,
+    pub schema_version: u32,
+    pub checksum: [u8; CHECKSUM_LEN],
+    pub key: Vec<u8>,
+}
+
+impl Header {
+    // The checksum is filled by set_checksum once the payload is written.
+    // A ttl too large to represent means the entry never expires.
+    pub fn new(
+        now: SystemTime, ttl: Option<Duration>, codec_id: u32, compression: u8, checksum_kind: u8, schema_version: u32, key: &[u8],
+    ) -> Self {
+        Self {
+            created_at: now,
+            expires_at: ttl.and_then(|ttl| now.checked_add(ttl)),
+            last_access: now,
+            hits: 0,
+            score: 0,
+            codec_id,
+            compression,
+            checksum_kind,
+            schema_version,
+            checksum: [0; CHECKSUM_LEN],
+            key: key.to_vec(),
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        match self.expires_at {
+            Some(t) => t <= now,
+            None => false,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.key.len());
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&to_millis(self.created_at).to_le_bytes());
+        buf.extend_from_slice(&self.expires_at.map(to_millis).unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&to_millis(self.last_access).to_le_bytes());
+        buf.extend_from_slice(&self.hits.to_le_bytes());
+        buf.extend_from_slice(&self.score.to_le_bytes());
+        buf.extend_from_slice(&self.codec_id.to_le_bytes());
+        buf.extend_from_slice(&[self.compression, self.checksum_kind, 0, 0]);
+        buf.extend_from_slice(&self.schema_version.to_le_bytes());
+        buf.extend_from_slice(&self.checksum);
+        buf.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.key);
+        buf
+    }
+
+    // Returns the header and the offset of the payload.
+    pub fn parse(bytes: &[u8]) -> Result<(Self, usize), CacheError> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            return Err(CacheError::Decode("not a cache entry".into()));
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(CacheError::Decode("entry header is truncated".into()));
+        }
+        let version = read_u16(bytes, 4);
+        if version != FORMAT_VERSION {
+            return Err(CacheError::UnsupportedFormat { version, flags: 0 });
+        }
+        let flags = read_u16(bytes, 6);
+        if flags != 0 {
+            return Err(CacheError::UnsupportedFormat { version, flags });
+        }
+        let created_at = from_millis(read_u64(bytes, 8));
+        let expires_at = match read_u64(bytes, 16) {
+            0 => None,
+            t => Some(from_millis(t)),
+        };
+        let last_access = from_millis(read_u64(bytes, 24));
+        let hits = read_u64(bytes, 32);
+        let score = read_u64(bytes, 40) as i64;
+        let codec_id = read_u32(bytes, 48);
+        let compression = bytes[52];
+        let checksum_kind = bytes[53];
+        let schema_version = read_u32(bytes, 56);
+        let mut checksum = [0; CHECKSUM_LEN];
+        checksum.copy_from_slice(&bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_LEN]);
+        let key_end = HEADER_LEN + read_u32(bytes, KEY_LEN_OFFSET) as usize;
+        if bytes.len() < key_end {
+            return Err(CacheError::Decode("entry key is truncated".into()));
+        }
+        let key = bytes[HEADER_LEN..key_end].to_vec();
+        Ok((Self {
+            created_at, expires_at, last_access, hits, score, codec_id, compression, checksum_kind, schema_version, checksum, key,
+        }, key_end))
+    }
+
+    // Reads the header from the start of an entry file. Returns the header and the offset of the payload.
+    pub fn read<R: Read>(r: &mut R) -> Result<(Self, usize), CacheError> {
+        let mut buf = Vec::with_capacity(HEADER_LEN);
+        r.by_ref().take(HEADER_LEN as u64).read_to_end(&mut buf)?;
+        if buf.len() == HEADER_LEN {
+            let key_len = read_u32(&buf, KEY_LEN_OFFSET) as u64;
+            r.take(key_len).read_to_end(&mut buf)?;
+        }
+        Self::parse(&buf)
+    }
+
+    // Reads only the header of the entry file.
+    pub fn read_from(path: &Path) -> io::Result<Self> {
+        Self::read(&mut File::open(path)?)
+            .map(|(header, _)| header)
+            .map_err(|e| match e {
+                CacheError::Io(e) | CacheError::DiskFull(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, e),
+            })
+    }
+}
+
+// Stores the checksum into an encoded header.
+pub(crate) fn set_checksum(header: &mut [u8], checksum: &[u8; CHECKSUM_LEN]) {
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_LEN].copy_from_slice(checksum);
+}
+
+// Stores the checksum into the header of an entry file.
+pub(crate) fn write_checksum<F: Write + Seek>(f: &mut F, checksum: &[u8; CHECKSUM_LEN]) -> io::Result<()> {
+    f.seek(SeekFrom::Start(CHECKSUM_OFFSET as u64))?;
+    f.write_all(checksum)
+}
+
+// Records an access to the entry. Access times are kept in the header because atime is unreliable.
+// Concurrent hits may be lost, which is fine for eviction purposes.
+pub(crate) fn touch(path: &Path, now: SystemTime) -> io::Result<()> {
+    record_hits(path, now, 1)
+}
+
+// Same as touch for a number of hits, e.g. served from memory. The last access is never moved back.
+pub(crate) fn record_hits(path: &Path, last_access: SystemTime, count: u64) -> io::Result<()> {
+    let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut buf = [0u8; 16];
+    f.seek(SeekFrom::Start(LAST_ACCESS_OFFSET))?;
+    f.read_exact(&mut buf)?;
+    let last_access = read_u64(&buf, 0).max(to_millis(last_access));
+    let hits = read_u64(&buf, 8).saturating_add(count);
+    buf[0..8].copy_from_slice(&last_access.to_le_bytes());
+    buf[8..16].copy_from_slice(&hits.to_le_bytes());
+    f.seek(SeekFrom::Start(LAST_ACCESS_OFFSET))?;
+    f.write_all(&buf)
+}
+
+pub(crate) fn set_score(path: &Path, score: i64) -> io::Result<()> {
+    let mut f = OpenOptions::new().write(true).open(path)?;
+    f.seek(SeekFrom::Start(SCORE_OFFSET))?;
+    f.write_all(&score.to_le_bytes())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(b)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(b)
+}
+
+fn to_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX)).unwrap_or(0)
+}
+
+fn from_millis(ms: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let now = from_millis(to_millis(SystemTime::now()));
+        let header = Header::new(now, Some(Duration::from_secs(60)), 7, 1, 2, 3, b"key");
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(b"payload");
+        set_checksum(&mut bytes, &[3; CHECKSUM_LEN]);
+
+        let (parsed, offset) = Header::parse(&bytes).unwrap();
+        assert_eq!(parsed, Header { checksum: [3; CHECKSUM_LEN], ..header });
+        assert_eq!(&bytes[offset..], b"payload");
+        assert!(!parsed.is_expired(now));
+        assert!(parsed.is_expired(now + Duration::from_secs(60)));
+
+        assert!(Header::parse(&bytes[..HEADER_LEN - 1]).is_err());
+        assert!(Header::parse(&bytes[..HEADER_LEN + 2]).is_err());
+    }
+
+    #[test]
+    fn huge_ttl_never_expires() {
+        let now = SystemTime::now();
+        for ttl in [Duration::MAX, Duration::from_secs(u64::MAX / 2)] {
+            let header = Header::new(now, Some(ttl), 0, 0, 0, 0, b"key");
+            let (parsed, _) = Header::parse(&header.to_bytes()).unwrap();
+            assert!(!parsed.is_expired(now + Duration::from_secs(100 * 365 * 24 * 60 * 60)));
+        }
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        let bytes = Header::new(SystemTime::now(), None, 0, 0, 0, 0, b"key").to_bytes();
+
+        let mut legacy = bytes.clone();
+        legacy[0] = b'x';
+        assert!(matches!(Header::parse(&legacy), Err(CacheError::Decode(_))));
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(Header::parse(&newer), Err(CacheError::UnsupportedFormat { .. })));
+
+        let mut flagged = bytes;
+        flagged[6] = 1;
+        assert!(matches!(Header::parse(&flagged), Err(CacheError::UnsupportedFormat { flags: 1, .. })));
+    }
+
+    #[test]
+    fn touch_updates_last_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("entry");
+        let created = from_millis(1000);
+        std::fs::write(&path, Header::new(created, None, 0, 0, 0, 0, b"entry").to_bytes()).unwrap();
+
+        touch(&path, from_millis(2000)).unwrap();
+        touch(&path, from_millis(3000)).unwrap();
+        record_hits(&path, from_millis(2500), 3).unwrap();
+        set_score(&path, -5).unwrap();
+        let header = Header::read_from(&path).unwrap();
+        assert_eq!(header.created_at, created);
+        assert_eq!(header.last_access, from_millis(3000));
+        assert_eq!(header.hits, 5);
+        assert_eq!(header.score, -5);
+        assert_eq!(header.key, b"entry");
+    }
+}
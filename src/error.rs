@@ -0,0 +1,147 @@
+use std::{fmt, io};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::codec::CodecError;
+
+/// Error type used throughout this crate.
+#[derive(Debug)]
+pub enum CacheError {
+    /// No base cache directory is available on this platform (see `dirs::cache_dir()`).
+    NoCacheDir,
+    /// The key cannot be used as a file name under the cache directory.
+    InvalidKey { key: PathBuf, reason: &'static str },
+    /// A value could not be encoded.
+    Encode(CodecError),
+    /// A cached entry could not be decoded.
+    Decode(CodecError),
+    /// The checksum of a cached entry does not match its content.
+    ChecksumMismatch,
+    /// A cached entry was written in a format version or with flags this version of the crate does not understand.
+    /// Entries of older versions are removed, entries of newer versions are treated as misses and left alone.
+    UnsupportedFormat { version: u16, flags: u16 },
+    /// Another thread or process did not finish computing the entry within the lock timeout.
+    LockTimeout { path: PathBuf, timeout: Duration },
+    /// The disk or the quota is full.
+    DiskFull(io::Error),
+    /// Any other I/O error.
+    Io(io::Error),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::NoCacheDir => write!(f, "cache directory is not available"),
+            CacheError::InvalidKey { key, reason } => write!(f, "invalid key {:?}: {}", key, reason),
+            CacheError::Encode(_) => write!(f, "cannot encode value"),
+            CacheError::Decode(_) => write!(f, "cannot decode cache entry"),
+            CacheError::ChecksumMismatch => write!(f, "checksum mismatch in cache entry"),
+            CacheError::UnsupportedFormat { version, flags } =>
+                write!(f, "unsupported cache entry format (version {}, flags {:#x})", version, flags),
+            CacheError::LockTimeout { path, timeout } =>
+                write!(f, "timed out after {:?} waiting for lock {:?}", timeout, path),
+            CacheError::DiskFull(_) => write!(f, "disk full"),
+            CacheError::Io(_) => write!(f, "cache I/O failed"),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CacheError::NoCacheDir => None,
+            CacheError::InvalidKey { .. } => None,
+            CacheError::Encode(e) => Some(&**e),
+            CacheError::Decode(e) => Some(&**e),
+            CacheError::ChecksumMismatch => None,
+            CacheError::UnsupportedFormat { .. } => None,
+            CacheError::LockTimeout { .. } => None,
+            CacheError::DiskFull(e) => Some(e),
+            CacheError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for CacheError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => CacheError::DiskFull(e),
+            _ => CacheError::Io(e),
+        }
+    }
+}
+
+/// Error returned by [`LocalFileCache::try_or_insert_with`](crate::LocalFileCache::try_or_insert_with).
+#[derive(Debug)]
+pub enum TryInsertError<E> {
+    /// The producer failed. Nothing was written to the cache.
+    Producer(E),
+    /// The cache itself failed.
+    Cache(CacheError),
+}
+
+impl<E> fmt::Display for TryInsertError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryInsertError::Producer(_) => write!(f, "producer failed"),
+            TryInsertError::Cache(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for TryInsertError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TryInsertError::Producer(e) => Some(e),
+            // Displayed as the cache error itself.
+            TryInsertError::Cache(e) => e.source(),
+        }
+    }
+}
+
+impl<E> From<CacheError> for TryInsertError<E> {
+    fn from(e: CacheError) -> Self {
+        TryInsertError::Cache(e)
+    }
+}
+
+impl<E> From<io::Error> for TryInsertError<E> {
+    fn from(e: io::Error) -> Self {
+        TryInsertError::Cache(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn io_errors_are_classified() {
+        let e: CacheError = io::Error::from(io::ErrorKind::StorageFull).into();
+        assert!(matches!(e, CacheError::DiskFull(_)));
+        assert!(e.source().is_some());
+
+        let e: CacheError = io::Error::from(io::ErrorKind::PermissionDenied).into();
+        assert!(matches!(e, CacheError::Io(_)));
+    }
+
+    #[test]
+    fn messages_are_not_repeated_in_the_chain() {
+        let chain = |e: &dyn Error| {
+            let mut messages = vec![e.to_string()];
+            let mut source = e.source();
+            while let Some(e) = source {
+                messages.push(e.to_string());
+                source = e.source();
+            }
+            messages
+        };
+        let e = CacheError::Io(io::Error::other("no space"));
+        assert_eq!(chain(&e), ["cache I/O failed", "no space"]);
+        let e = TryInsertError::<io::Error>::Cache(e);
+        assert_eq!(chain(&e), ["cache I/O failed", "no space"]);
+        let e = TryInsertError::<io::Error>::Producer(io::Error::other("offline"));
+        assert_eq!(chain(&e), ["producer failed", "offline"]);
+    }
+}
@@ -0,0 +1,199 @@
+//! Eviction policies used when the cache exceeds its size or entry-count limit.
+
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::entry::Header;
+use crate::key;
+use crate::CacheError;
+
+/// A cache entry as seen by an [`EvictionPolicy`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct EntryInfo {
+    /// Path of the entry file.
+    pub path: PathBuf,
+    /// Key the entry was stored under.
+    pub key: Vec<u8>,
+    /// Size of the entry file in bytes.
+    pub size: u64,
+    pub created_at: SystemTime,
+    pub last_access: SystemTime,
+    /// Number of cache hits since the entry was written.
+    pub hits: u64,
+    /// Application defined score set by [`LocalFileCache::set_score`](crate::LocalFileCache::set_score).
+    pub score: i64,
+}
+
+/// Decides which entries are removed first when a limit is exceeded.
+pub trait EvictionPolicy: Send + Sync {
+    /// Sorts `entries` so that the entries to evict first come first.
+    fn order(&self, entries: &mut [EntryInfo]);
+}
+
+/// Evicts the least recently used entries first. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn order(&self, entries: &mut [EntryInfo]) {
+        entries.sort_by_key(|e| e.last_access);
+    }
+}
+
+/// Evicts the oldest entries first.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fifo;
+
+impl EvictionPolicy for Fifo {
+    fn order(&self, entries: &mut [EntryInfo]) {
+        entries.sort_by_key(|e| e.created_at);
+    }
+}
+
+/// Evicts the least frequently used entries first. Ties are broken by recency.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lfu;
+
+impl EvictionPolicy for Lfu {
+    fn order(&self, entries: &mut [EntryInfo]) {
+        entries.sort_by_key(|e| (e.hits, e.last_access));
+    }
+}
+
+/// Evicts the largest entries first.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargestFirst;
+
+impl EvictionPolicy for LargestFirst {
+    fn order(&self, entries: &mut [EntryInfo]) {
+        entries.sort_by_key(|e| Reverse(e.size));
+    }
+}
+
+// Removes entries in the order given by the policy until both limits are satisfied and returns their paths.
+// Entries whose header cannot be read are removed first. Headers are only read if a limit is exceeded.
+pub(crate) fn evict(
+    dir: &Path, max_bytes: Option<u64>, max_entries: Option<usize>, policy: &dyn EvictionPolicy,
+) -> Result<Vec<PathBuf>, CacheError> {
+    let over = |bytes: u64, count: usize| {
+        max_bytes.is_some_and(|max| max < bytes) || max_entries.is_some_and(|max| max < count)
+    };
+    let (mut total_bytes, mut total_entries) = (0, 0);
+    walk(dir, &mut |_, size| {
+        total_bytes += size;
+        total_entries += 1;
+    })?;
+    let mut removed = Vec::new();
+    if !over(total_bytes, total_entries) {
+        return Ok(removed);
+    }
+
+    let mut entries = Vec::new();
+    let mut unreadable = Vec::new();
+    scan(dir, &mut entries, &mut unreadable)?;
+    // Entries may have come and gone since they were counted.
+    total_bytes = entries.iter().map(|e| e.size).chain(unreadable.iter().map(|(_, size)| *size)).sum();
+    total_entries = entries.len() + unreadable.len();
+
+    policy.order(&mut entries);
+    let victims = unreadable.into_iter().chain(entries.into_iter().map(|e| (e.path, e.size)));
+    for (path, size) in victims {
+        if !over(total_bytes, total_entries) {
+            break;
+        }
+        match fs::remove_file(&path) {
+            Ok(_) => {},
+            Err(e) => match e.kind() {
+                // Already removed by someone else.
+                io::ErrorKind::NotFound => {},
+                _ => return Err(e.into()),
+            }
+        }
+        total_bytes -= size;
+        total_entries -= 1;
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+pub(crate) fn scan(dir: &Path, entries: &mut Vec<EntryInfo>, unreadable: &mut Vec<(PathBuf, u64)>) -> io::Result<()> {
+    walk(dir, &mut |path, size| match Header::read_from(&path) {
+        Ok(h) => entries.push(EntryInfo {
+            path, size,
+            key: h.key,
+            created_at: h.created_at,
+            last_access: h.last_access,
+            hits: h.hits,
+            score: h.score,
+        }),
+        Err(_) => unreadable.push((path, size)),
+    })
+}
+
+// Calls f with the path and size of every entry file under dir.
+fn walk(dir: &Path, f: &mut dyn FnMut(PathBuf, u64)) -> io::Result<()> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(d) => d,
+        Err(e) => match e.kind() {
+            io::ErrorKind::NotFound => return Ok(()),
+            _ => return Err(e),
+        }
+    };
+    for dir_entry in read_dir {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        let file_type = dir_entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&path, f)?;
+        } else if file_type.is_file() && !key::is_reserved(&path) {
+            match dir_entry.metadata() {
+                Ok(m) => f(path, m.len()),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::NotFound => continue,
+                    _ => return Err(e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn info(name: &str, size: u64, created: u64, access: u64, hits: u64) -> EntryInfo {
+        EntryInfo {
+            path: PathBuf::from(name),
+            key: name.as_bytes().to_vec(),
+            size,
+            created_at: UNIX_EPOCH + Duration::from_secs(created),
+            last_access: UNIX_EPOCH + Duration::from_secs(access),
+            hits,
+            score: 0,
+        }
+    }
+
+    fn names(policy: &dyn EvictionPolicy) -> Vec<String> {
+        let mut entries = vec![
+            info("a", 10, 1, 5, 3),
+            info("b", 30, 2, 4, 1),
+            info("c", 20, 3, 6, 1),
+        ];
+        policy.order(&mut entries);
+        entries.iter().map(|e| e.path.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn built_in_policies() {
+        assert_eq!(names(&Lru), vec!["b", "a", "c"]);
+        assert_eq!(names(&Fifo), vec!["a", "b", "c"]);
+        assert_eq!(names(&Lfu), vec!["b", "c", "a"]);
+        assert_eq!(names(&LargestFirst), vec!["b", "c", "a"]);
+    }
+}
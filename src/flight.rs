@@ -0,0 +1,252 @@
+// Per-key locks that let only one thread, and with lock_file only one process, compute a missing entry at a time.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::CacheError;
+
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// One thread leads the computation of a key while the others wait for what it publishes.
+// Clones share the same keys.
+pub(crate) struct KeyLocks<V> {
+    flights: Arc<Mutex<HashMap<PathBuf, Arc<Flight<V>>>>>,
+}
+
+struct Flight<V> {
+    // None while the leader runs, then what it published.
+    outcome: Mutex<Option<Option<V>>>,
+    done: Condvar,
+}
+
+pub(crate) enum Joined<V> {
+    Leader(KeyGuard<V>),
+    // What the leader published, None if it had nothing to share, e.g. because its producer failed.
+    Waited(Option<V>),
+}
+
+impl<V> Default for KeyLocks<V> {
+    fn default() -> Self {
+        Self { flights: Arc::default() }
+    }
+}
+
+impl<V> Clone for KeyLocks<V> {
+    fn clone(&self) -> Self {
+        Self { flights: self.flights.clone() }
+    }
+}
+
+impl<V: Clone> KeyLocks<V> {
+    // Leads the path if no other thread does, otherwise waits up to timeout for the leader to finish.
+    pub fn join(&self, path: &Path, timeout: Duration) -> Result<Joined<V>, CacheError> {
+        self.join_until(path, Instant::now().checked_add(timeout), timeout)
+    }
+
+    // Blocks until no other thread leads the path and leads it.
+    pub fn lock(&self, path: &Path, timeout: Duration) -> Result<KeyGuard<V>, CacheError> {
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            if let Joined::Leader(guard) = self.join_until(path, deadline, timeout)? {
+                return Ok(guard);
+            }
+        }
+    }
+
+    // A deadline of None never expires.
+    fn join_until(&self, path: &Path, deadline: Option<Instant>, timeout: Duration) -> Result<Joined<V>, CacheError> {
+        let flight = match self.flights().entry(path.to_owned()) {
+            Entry::Occupied(e) => e.get().clone(),
+            Entry::Vacant(e) => {
+                let flight = Arc::new(Flight { outcome: Mutex::new(None), done: Condvar::new() });
+                e.insert(flight.clone());
+                return Ok(Joined::Leader(KeyGuard { locks: self.clone(), path: path.to_owned(), flight, published: None }));
+            },
+        };
+        let mut outcome = flight.outcome();
+        loop {
+            if let Some(published) = &*outcome {
+                return Ok(Joined::Waited(published.clone()));
+            }
+            outcome = match deadline {
+                None => flight.done.wait(outcome).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        return Err(CacheError::LockTimeout { path: path.to_owned(), timeout });
+                    }
+                    flight.done.wait_timeout(outcome, deadline - now).unwrap_or_else(PoisonError::into_inner).0
+                },
+            };
+        }
+    }
+}
+
+impl<V> KeyLocks<V> {
+    // The map stays consistent even if a holder panicked, so poisoning is ignored.
+    fn flights(&self) -> MutexGuard<'_, HashMap<PathBuf, Arc<Flight<V>>>> {
+        self.flights.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<V> Flight<V> {
+    fn outcome(&self) -> MutexGuard<'_, Option<Option<V>>> {
+        self.outcome.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Held by the leader of a path. The waiting threads are released when it is dropped.
+pub(crate) struct KeyGuard<V> {
+    locks: KeyLocks<V>,
+    path: PathBuf,
+    flight: Arc<Flight<V>>,
+    published: Option<V>,
+}
+
+impl<V> KeyGuard<V> {
+    // Hands v to the threads waiting for this leader.
+    pub fn publish(&mut self, v: V) {
+        self.published = Some(v);
+    }
+}
+
+impl<V> Drop for KeyGuard<V> {
+    fn drop(&mut self) {
+        self.locks.flights().remove(&self.path);
+        *self.flight.outcome() = Some(self.published.take());
+        self.flight.done.notify_all();
+    }
+}
+
+// Takes the advisory lock on the file at path, creating it if needed, polling until the timeout elapses.
+// None if the file system does not support locking.
+pub(crate) fn lock_file(path: &Path, timeout: Duration) -> Result<Option<FileLock>, CacheError> {
+    let deadline = Instant::now() + timeout;
+    let mut interval = Duration::from_millis(1);
+    let mut f = open_lock_file(path)?;
+    loop {
+        match f.try_lock() {
+            // The previous holder may have removed the file while we were waiting for it.
+            Ok(()) if is_current(&f, path)? => return Ok(Some(FileLock { file: f, path: path.to_owned() })),
+            Ok(()) => {
+                f = open_lock_file(path)?;
+                continue;
+            },
+            Err(TryLockError::WouldBlock) => {},
+            Err(TryLockError::Error(e)) if e.kind() == io::ErrorKind::Unsupported => return Ok(None),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        let now = Instant::now();
+        if deadline <= now {
+            return Err(CacheError::LockTimeout { path: path.to_owned(), timeout });
+        }
+        thread::sleep(interval.min(deadline - now));
+        interval = (interval * 2).min(MAX_POLL_INTERVAL);
+    }
+}
+
+// A held file lock. The lock file is removed before the lock is released so that it does not outlive the computation.
+#[derive(Debug)]
+pub(crate) struct FileLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if cfg!(unix) {
+            let _ = fs::remove_file(&self.path);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+fn open_lock_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create(true).truncate(false).open(path)
+}
+
+// True if the opened file is still the one at path.
+#[cfg(unix)]
+pub(crate) fn is_current(f: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let locked = f.metadata()?;
+    match fs::metadata(path) {
+        Ok(m) => Ok(m.dev() == locked.dev() && m.ino() == locked.ino()),
+        Err(e) => match e.kind() {
+            io::ErrorKind::NotFound => Ok(false),
+            _ => Err(e),
+        }
+    }
+}
+
+// Files cannot be compared on other platforms. Lock files are never removed there.
+#[cfg(not(unix))]
+pub(crate) fn is_current(_f: &File, _path: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn only_one_holder_per_key() {
+        let locks = KeyLocks::<()>::default();
+        let holders = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let _guard = locks.lock(Path::new("key"), Duration::MAX).unwrap();
+                    assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+                    // Other keys are not blocked.
+                    drop(locks.lock(Path::new("other"), Duration::ZERO).unwrap());
+                    thread::sleep(Duration::from_millis(10));
+                    holders.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert!(locks.flights().is_empty());
+    }
+
+    #[test]
+    fn waiters_get_what_the_leader_published() {
+        let locks = KeyLocks::<u32>::default();
+        let Ok(Joined::Leader(mut guard)) = locks.join(Path::new("key"), Duration::ZERO) else { panic!() };
+        let err = locks.join(Path::new("key"), Duration::from_millis(20)).err().unwrap();
+        assert!(matches!(err, CacheError::LockTimeout { .. }));
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| match locks.join(Path::new("key"), Duration::MAX).ok().unwrap() {
+                Joined::Waited(published) => published,
+                Joined::Leader(_) => panic!("the key is led by the main thread"),
+            });
+            thread::sleep(Duration::from_millis(20));
+            guard.publish(42);
+            drop(guard);
+            assert_eq!(waiter.join().unwrap(), Some(42));
+        });
+        assert!(matches!(locks.join(Path::new("key"), Duration::ZERO), Ok(Joined::Leader(_))));
+    }
+
+    #[test]
+    fn lock_file_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("entry.lock");
+        let held = lock_file(&path, Duration::ZERO).unwrap();
+        assert!(held.is_some());
+
+        let err = lock_file(&path, Duration::from_millis(20)).unwrap_err();
+        assert!(matches!(err, CacheError::LockTimeout { .. }));
+
+        drop(held);
+        assert_eq!(path.exists(), cfg!(not(unix)));
+        assert!(lock_file(&path, Duration::ZERO).unwrap().is_some());
+    }
+}
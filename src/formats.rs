@@ -0,0 +1,123 @@
+//! Serialization formats for [`LocalFileCache::with_serde`](crate::LocalFileCache::with_serde).
+
+use std::io::Write;
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::codec::{Codec, CodecError};
+
+/// A serde data format that can be used to store cache entries.
+pub trait SerdeFormat {
+    /// Codec id recorded in the entries written in this format.
+    const ID: u32;
+    fn to_writer<T: Serialize>(w: &mut dyn Write, value: &T) -> Result<(), CodecError>;
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// [`Codec`] storing values in the serde format `F`.
+pub struct SerdeCodec<F> {
+    format: PhantomData<fn() -> F>,
+}
+
+impl<F> SerdeCodec<F> {
+    pub fn new() -> Self {
+        Self { format: PhantomData }
+    }
+}
+
+impl<F> Default for SerdeCodec<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, F: SerdeFormat> Codec<T> for SerdeCodec<F> {
+    fn id(&self) -> u32 {
+        F::ID
+    }
+
+    fn encode(&self, value: &T, w: &mut dyn Write) -> Result<(), CodecError> {
+        F::to_writer(w, value)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        F::from_slice(bytes)
+    }
+}
+
+/// JSON via `serde_json`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl SerdeFormat for Json {
+    const ID: u32 = 1;
+
+    fn to_writer<T: Serialize>(w: &mut dyn Write, value: &T) -> Result<(), CodecError> {
+        Ok(serde_json::to_writer(w, value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Bincode via `bincode`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl SerdeFormat for Bincode {
+    const ID: u32 = 2;
+
+    fn to_writer<T: Serialize>(w: &mut dyn Write, value: &T) -> Result<(), CodecError> {
+        Ok(bincode::serialize_into(w, value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// MessagePack via `rmp-serde`. Structs are encoded as maps so fields can be added later.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+impl SerdeFormat for MessagePack {
+    const ID: u32 = 3;
+
+    fn to_writer<T: Serialize>(w: &mut dyn Write, value: &T) -> Result<(), CodecError> {
+        Ok(rmp_serde::encode::write_named(w, value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Item {
+        id: u32,
+        name: String,
+    }
+
+    fn round_trip<F: SerdeFormat>() {
+        let codec = SerdeCodec::<F>::new();
+        let item = Item { id: 1, name: "one".to_owned() };
+        let mut bin = Vec::new();
+        codec.encode(&item, &mut bin).unwrap();
+        assert_eq!(Codec::<Item>::decode(&codec, &bin).unwrap(), item);
+        assert!(Codec::<Item>::decode(&codec, &bin[..bin.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        round_trip::<Json>();
+        round_trip::<Bincode>();
+        round_trip::<MessagePack>();
+    }
+}
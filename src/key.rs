@@ -0,0 +1,102 @@
+use std::path::{Component, Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::CacheError;
+
+/// Extension of the temporary files written before an entry is renamed into place.
+pub(crate) const TEMP_EXTENSION: &str = "save";
+/// Extension of the files locked while an entry is computed.
+pub(crate) const LOCK_EXTENSION: &str = "lock";
+
+/// How keys are mapped to entry files under the cache directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyMode {
+    /// The key is used as a path relative to the cache directory.
+    #[default]
+    Path,
+    /// The file name is the hex encoded SHA-256 digest of the key, so keys of any length and content can be used.
+    /// The original key is kept in the entry and is available through [`EntryInfo::key`](crate::EntryInfo::key).
+    Hashed,
+}
+
+impl KeyMode {
+    pub(crate) fn relative_path(&self, k: &Path) -> Result<PathBuf, CacheError> {
+        match self {
+            KeyMode::Path => {
+                validate(k)?;
+                Ok(k.to_owned())
+            },
+            KeyMode::Hashed => Ok(PathBuf::from(format!("{:x}", Sha256::digest(key_bytes(k))))),
+        }
+    }
+}
+
+// A key must be a relative path that stays inside the cache directory and names a file.
+fn validate(k: &Path) -> Result<(), CacheError> {
+    let invalid = |reason| Err(CacheError::InvalidKey { key: k.to_owned(), reason });
+    let mut last = None;
+    for c in k.components() {
+        match c {
+            Component::Prefix(_) | Component::RootDir => return invalid("absolute path"),
+            Component::ParentDir => return invalid("contains '..'"),
+            Component::CurDir => {},
+            Component::Normal(name) => last = Some(name),
+        }
+    }
+    match last {
+        None => invalid("empty name"),
+        Some(name) if is_reserved(Path::new(name)) => invalid("reserved suffix"),
+        Some(_) => Ok(()),
+    }
+}
+
+// True for the temporary and lock files kept next to entries.
+pub(crate) fn is_reserved(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == TEMP_EXTENSION || ext == LOCK_EXTENSION)
+}
+
+// Path of the temporary file used while writing the entry at path.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    append_extension(path, TEMP_EXTENSION)
+}
+
+// Path of the lock file of the entry at path.
+pub(crate) fn lock_path(path: &Path) -> PathBuf {
+    append_extension(path, LOCK_EXTENSION)
+}
+
+fn append_extension(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+pub(crate) fn key_bytes(k: &Path) -> &[u8] {
+    k.as_os_str().as_encoded_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_key_is_a_single_file_name() {
+        let path = KeyMode::Hashed.relative_path(Path::new("../../.ssh/x")).unwrap();
+        assert_eq!(path.as_os_str().len(), 64);
+        assert_eq!(path.components().count(), 1);
+        assert_eq!(path, KeyMode::Hashed.relative_path(Path::new("../../.ssh/x")).unwrap());
+        assert_ne!(path, KeyMode::Hashed.relative_path(Path::new("../../.ssh/y")).unwrap());
+    }
+
+    #[test]
+    fn path_keys_are_validated() {
+        for k in ["data", "a/b", "./a", "a.json", "a.save/b"] {
+            assert_eq!(KeyMode::Path.relative_path(Path::new(k)).unwrap(), PathBuf::from(k));
+        }
+        for k in ["", ".", "/etc/passwd", "../x", "a/../../x", "a.save", "a/b.save", "a.lock"] {
+            assert!(matches!(KeyMode::Path.relative_path(Path::new(k)), Err(CacheError::InvalidKey { .. })), "{}", k);
+        }
+    }
+}
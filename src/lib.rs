@@ -1,139 +1,1465 @@
-use std::{path::{Path, PathBuf}, fs::{File, self, OpenOptions}, io::{Error, self}};
-use std::io::Read;
-use std::io::Write;
+use std::{path::{Path, PathBuf}, fs::{File, self, OpenOptions}};
+use std::convert::Infallible;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use dirs;
+#[cfg(feature = "tokio")]
+mod async_cache;
+mod builder;
+mod checksum;
+pub mod codec;
+mod compression;
+mod durability;
+pub mod entry;
+mod error;
+pub mod eviction;
+mod flight;
+#[cfg(feature = "serde")]
+pub mod formats;
+mod key;
+mod memory;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod stream;
 
-pub struct LocalFileCache<T> {
+use entry::Header;
+use flight::Joined;
+use memory::MemoryTier;
+use stream::HashingWriter;
+
+#[cfg(feature = "tokio")]
+pub use async_cache::AsyncLocalFileCache;
+pub use builder::LocalFileCacheBuilder;
+pub use checksum::Checksum;
+pub use codec::{Codec, FnCodec, Uncacheable};
+pub use compression::Compression;
+pub use durability::Durability;
+pub use error::{CacheError, TryInsertError};
+pub use eviction::{EntryInfo, EvictionPolicy};
+pub use key::KeyMode;
+#[cfg(feature = "mmap")]
+pub use mmap::EntryMap;
+pub use stream::EntryReader;
+
+/// Serializes a value into bytes. Returning `None` skips caching the value.
+pub type ToU8<T> = Box<dyn Fn(&T) -> Option<Vec<u8>> + Send + Sync>;
+/// Deserializes bytes read from a cache file. An `Err` marks the entry as corrupt.
+pub type FromU8<T> = Box<dyn Fn(&[u8]) -> Result<T, DecodeError> + Send + Sync>;
+/// Error returned by a [`FromU8`] decoder.
+pub type DecodeError = Box<dyn std::error::Error + Send + Sync>;
+/// Called with the path of an entry that failed to decode and the reason.
+pub type CorruptionHook = Box<dyn Fn(&Path, &CacheError) + Send + Sync>;
+
+/// A cache of values stored as files.
+///
+/// The cache is `Send + Sync` when `T` and the codec are, so one instance can be shared between threads with `Arc`.
+pub struct LocalFileCache<T, C = FnCodec<T>> {
     dir: PathBuf,
-    to_u8: Box<dyn Fn(&T) -> Option<Vec<u8>>>,
-    from_u8: Box<dyn Fn(&[u8]) -> T>,
+    codec: C,
+    value: PhantomData<fn() -> T>,
+    on_corrupt: Option<CorruptionHook>,
+    ttl: Option<Duration>,
+    max_bytes: Option<u64>,
+    max_entries: Option<usize>,
+    eviction_policy: Box<dyn EvictionPolicy>,
+    key_mode: KeyMode,
+    compression: Compression,
+    checksum: Checksum,
+    schema_version: u32,
+    durability: Durability,
+    lock_timeout: Duration,
+    flights: flight::KeyLocks<Shared<T>>,
+    memory: Option<MemoryTier<T>>,
 }
 
 impl<T> LocalFileCache<T> {
-    pub fn new<P: AsRef<Path>>(sub_path: P, to_u8: Box<dyn Fn(&T) -> Option<Vec<u8>>>, from_u8: Box<dyn Fn(&[u8]) -> T>) -> Option<Self> {
-        dirs::cache_dir().map(|mut base_dir| {
-            base_dir.push(sub_path);
-            Self {
-                dir: base_dir,
-                to_u8, from_u8,
-            }
-        })
+    /// Creates a cache that encodes values with the given closures. See [`FnCodec`].
+    /// Shortcut for [`LocalFileCacheBuilder::build_with`] with the default settings.
+    pub fn new<P: AsRef<Path>>(sub_path: P, to_u8: ToU8<T>, from_u8: FromU8<T>) -> Result<Self, CacheError> {
+        LocalFileCacheBuilder::new(sub_path).build_with(to_u8, from_u8)
     }
 
-    pub fn invalidate<P: AsRef<Path>>(sub_path: P) -> Option<io::Result<()>> {
-        dirs::cache_dir().map(|mut base_dir| {
-            base_dir.push(sub_path);
-            fs::remove_dir_all(&base_dir)
-        })
+    /// Same as [`Self::new`] but entries written under another schema version are ignored and removed.
+    /// Bump the version whenever the layout of `T` changes.
+    pub fn new_versioned<P: AsRef<Path>>(
+        sub_path: P, schema_version: u32, to_u8: ToU8<T>, from_u8: FromU8<T>,
+    ) -> Result<Self, CacheError> {
+        Ok(Self::new(sub_path, to_u8, from_u8)?.with_schema_version(schema_version))
+    }
+
+    /// Removes the directory used by [`Self::new`] for `sub_path`, resolved like [`LocalFileCacheBuilder::dir`].
+    pub fn invalidate<P: AsRef<Path>>(sub_path: P) -> Result<(), CacheError> {
+        Ok(fs::remove_dir_all(LocalFileCacheBuilder::new(sub_path).dir()?)?)
+    }
+
+    fn save_to(path: &Path, bytes: &[u8], durability: Durability) -> Result<(), CacheError> {
+        // More than one program may save the same cache entry simultaneously.
+        // 1) Save file named "xxx.save" with create_new(true). It will be failed if the file with the same name already exists.
+        // 2) If the same named file already exists, just skip this method.
+        //    A file not modified for STALE_TEMP_AGE was left by a crashed writer. It is removed and the save is retried.
+        // 3) Otherwise, rename "xxx.save" to "xxx".
+
+        let save_path = key::temp_path(path);
+        let Some(mut f) = create_temp(&save_path)? else {
+            return Ok(());
+        };
+        f.write_all(bytes)?;
+        durability.sync_file(&f)?;
+        drop(f);
+        fs::rename(&save_path, path)?;
+        if let Some(parent) = path.parent() {
+            durability.sync_dir(parent)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> LocalFileCache<T> {
+    /// Creates a cache that stores values in the given serde format, e.g. `LocalFileCache::<Item>::with_serde::<formats::Json>("my_app")`.
+    pub fn with_serde<F: formats::SerdeFormat>(sub_path: impl AsRef<Path>) -> Result<LocalFileCache<T, formats::SerdeCodec<F>>, CacheError> {
+        LocalFileCacheBuilder::new(sub_path).build_serde()
+    }
+}
+
+impl<T, C: Codec<T>> LocalFileCache<T, C> {
+    /// Shortcut for [`LocalFileCacheBuilder::build`] with the default settings.
+    pub fn with_codec<P: AsRef<Path>>(sub_path: P, codec: C) -> Result<Self, CacheError> {
+        LocalFileCacheBuilder::new(sub_path).build(codec)
+    }
+
+    /// Sets the default time-to-live of entries. Expired entries are treated as misses.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Limits the total size of the cache files. Entries are evicted after each insert.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Limits the number of cache files. Entries are evicted after each insert.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Sets the policy that decides which entries are evicted first. Defaults to [`eviction::Lru`].
+    pub fn with_eviction_policy<P: EvictionPolicy + 'static>(mut self, policy: P) -> Self {
+        self.eviction_policy = Box::new(policy);
+        self
+    }
+
+    /// Sets how keys are mapped to file names. Defaults to [`KeyMode::Path`].
+    pub fn with_key_mode(mut self, key_mode: KeyMode) -> Self {
+        self.key_mode = key_mode;
+        self
+    }
+
+    /// Sets the compression of new entries. Existing entries are decompressed according to their own header.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the checksum written with new entries. Defaults to [`Checksum::Crc32`].
+    /// Entries whose checksum does not match are treated as corrupt.
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Keeps up to `max_entries` decoded values in memory, least recently used first out.
+    /// The memory tier is used by [`Self::get_shared`] and [`Self::or_insert_with_shared`] and is updated by every
+    /// write through this instance. Writes by other instances or processes are not seen until the value is dropped
+    /// from memory or expires. Hits served from memory are recorded in the entry file for eviction at most once a
+    /// second per value and when the value is dropped from memory.
+    pub fn with_memory_capacity(mut self, max_entries: usize) -> Self {
+        self.memory.get_or_insert_with(MemoryTier::new).set_max_entries(max_entries);
+        self
+    }
+
+    /// Limits the total weight of the values kept in memory, see [`Self::with_memory_capacity`].
+    /// Values heavier than `max_weight` are not kept.
+    pub fn with_memory_weight<W>(mut self, max_weight: usize, weigher: W) -> Self
+        where W: Fn(&T) -> usize + Send + Sync + 'static
+    {
+        self.memory.get_or_insert_with(MemoryTier::new).set_max_weight(max_weight, Box::new(weigher));
+        self
+    }
+
+    /// Sets how new entries are synced to disk. Defaults to [`Durability::FileAndDir`].
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the application schema version. Entries written under another version are treated as misses
+    /// and removed when they are accessed. Defaults to 0.
+    pub fn with_schema_version(mut self, schema_version: u32) -> Self {
+        self.schema_version = schema_version;
+        self
+    }
+
+    /// Sets how long `or_insert_with` waits for another thread or process computing the same key
+    /// before failing with [`CacheError::LockTimeout`]. Defaults to 60 seconds.
+    ///
+    /// Processes are coordinated with an advisory lock on a `<key>.lock` file next to the entry, which exists
+    /// while the value is computed.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
     }
 
-    pub fn flush(&self) -> Result<(), Error> {
-        match fs::remove_dir_all(&self.dir) {
+    /// Registers a hook that is called whenever a cached entry is corrupt, e.g. fails to decode.
+    /// The entry is removed and regenerated regardless of the hook.
+    pub fn on_corrupt<H>(mut self, hook: H) -> Self
+        where H: Fn(&Path, &CacheError) + Send + Sync + 'static
+    {
+        self.on_corrupt = Some(Box::new(hook));
+        self
+    }
+
+    pub fn flush(&self) -> Result<(), CacheError> {
+        let result = match fs::remove_dir_all(&self.dir) {
             Ok(_) => {
-                fs::create_dir(&self.dir)
+                fs::create_dir(&self.dir).map_err(CacheError::from)
             },
             Err(e) => match e.kind() {
                 std::io::ErrorKind::NotFound => Ok(()),
-                _ => return Err(e),
+                _ => Err(e.into()),
             }
+        };
+        if let Some(memory) = &self.memory {
+            memory.clear();
         }
+        result
     }
 
-    pub fn or_insert_with<K, F>(&self, k: K, f: F) -> Result<T, Error>
+    /// Returns the cached value, or calls `f` and caches its result on a miss.
+    ///
+    /// Concurrent misses of the same key are coalesced, also across processes sharing the cache directory:
+    /// one caller runs its producer while the others wait for its value. If the value could not be cached, e.g. the
+    /// producer failed, the waiting callers run their own producers. See [`Self::with_lock_timeout`].
+    pub fn or_insert_with<K, F>(&self, k: K, f: F) -> Result<T, CacheError>
         where K: AsRef<Path>, F: FnOnce() -> T
     {
-        let mut buf = PathBuf::new();
-        buf.push(&self.dir);
-        fs::create_dir_all(buf.as_path())?;
+        self.try_or_insert_with(k, || Ok::<T, Infallible>(f())).map_err(|e| match e {
+            TryInsertError::Cache(e) => e,
+            TryInsertError::Producer(e) => match e {},
+        })
+    }
 
-        buf.push(k);
-        let path = buf.as_path();
-    
-        let mut fh = match File::open(path) {
-            Ok(fh) => Ok(fh),
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::NotFound => {
-                    let r = f();
-                    if let Some(bin) = (self.to_u8)(&r) {
-                        Self::save_to(path, &bin)?;
-                    }
+    /// Same as [`Self::or_insert_with`] but the producer may fail.
+    /// An `Err` from the producer is returned as [`TryInsertError::Producer`] and is never cached.
+    pub fn try_or_insert_with<K, F, E>(&self, k: K, f: F) -> Result<T, TryInsertError<E>>
+        where K: AsRef<Path>, F: FnOnce() -> Result<T, E>
+    {
+        self.get_or_insert(k, || f().map(|v| (v, None)))
+    }
 
-                    return Ok(r);
+    /// Same as [`Self::or_insert_with`] but the producer also returns the lifetime of the entry.
+    /// `None` falls back to the default set by [`Self::with_ttl`].
+    pub fn or_insert_with_ttl<K, F>(&self, k: K, f: F) -> Result<T, CacheError>
+        where K: AsRef<Path>, F: FnOnce() -> (T, Option<Duration>)
+    {
+        self.get_or_insert(k, || Ok::<_, Infallible>(f())).map_err(|e| match e {
+            TryInsertError::Cache(e) => e,
+            TryInsertError::Producer(e) => match e {},
+        })
+    }
+
+    fn get_or_insert<K, F, E>(&self, k: K, f: F) -> Result<T, TryInsertError<E>>
+        where K: AsRef<Path>, F: FnOnce() -> Result<(T, Option<Duration>), E>
+    {
+        let k = k.as_ref();
+        let path = self.entry_path(k)?;
+
+        if let Some(v) = self.load(&path, k)? {
+            return Ok(v);
+        }
+        // Other threads missing the same key get the value of this one. Other processes wait for the file lock and
+        // then read the entry written by this one.
+        let mut guard = match self.flights.join(&path, self.lock_timeout)? {
+            Joined::Leader(guard) => guard,
+            Joined::Waited(Some(shared)) => return Ok(self.codec.decode(&shared.bytes).map_err(CacheError::Decode)?),
+            // Nothing was cached, so the waiting threads run their own producers at the same time. They only take the
+            // locks to write their values.
+            Joined::Waited(None) => {
+                if let Some(v) = self.load(&path, k)? {
+                    return Ok(v);
+                }
+                let (r, ttl) = f().map_err(TryInsertError::Producer)?;
+                let (mut guard, file_lock) = self.lock(&path)?;
+                if let Some(stored) = self.store(&path, k, &r, ttl, file_lock.is_some())? {
+                    guard.publish(Shared { bytes: stored.bytes, value: None });
                 }
-                _ => Err(e),
+                return Ok(r);
             },
-        }?;
-        let mut buffer: Vec<u8> = vec![0; fh.metadata()?.len() as usize];
-        fh.read_exact(&mut buffer)?;
-        Ok((self.from_u8)(&buffer))
+        };
+        let file_lock = self.lock_file(&path)?;
+        if let Some(v) = self.load(&path, k)? {
+            return Ok(v);
+        }
+
+        let (r, ttl) = f().map_err(TryInsertError::Producer)?;
+        if let Some(stored) = self.store(&path, k, &r, ttl, file_lock.is_some())? {
+            guard.publish(Shared { bytes: stored.bytes, value: None });
+        }
+        Ok(r)
     }
 
-    fn save_to(path: &Path, bytes: &[u8]) -> Result<(), Error> {
-        // More than one program may save the same cache entry simultaneously.
-        // 1) Save file named "xxx.save" with create_new(true). It will be failed if the file with the same name already exists.
-        // 2) If the same named file already exists, just skip this method.
-        // 3) Otherwise, rename "xxx.save" to "xxx".
+    // Takes the in-process and the file lock of the entry. The file lock is None if the file system does not support it.
+    fn lock(&self, path: &Path) -> Result<(flight::KeyGuard<Shared<T>>, Option<flight::FileLock>), CacheError> {
+        let guard = self.flights.lock(path, self.lock_timeout)?;
+        Ok((guard, self.lock_file(path)?))
+    }
 
-        let mut save_path = PathBuf::new();
-        save_path.push(path);
-        save_path.set_extension("save");
+    fn lock_file(&self, path: &Path) -> Result<Option<flight::FileLock>, CacheError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        flight::lock_file(&key::lock_path(path), self.lock_timeout)
+    }
 
-        let mut f = match OpenOptions::new().write(true).create_new(true).open(&save_path) {
-            Ok(file) => Ok(file),
-            Err(e) => if e.kind() == std::io::ErrorKind::AlreadyExists {
-                return Ok(());
-            } else { Err(e) }
-        }?;
-        f.write_all(bytes)?;
-        fs::rename(&save_path, path)?;
+    /// Returns the cached value, or `None` if the entry is missing, expired or corrupt.
+    pub fn get<K: AsRef<Path>>(&self, k: K) -> Result<Option<T>, CacheError> {
+        let k = k.as_ref();
+        self.load(&self.entry_path(k)?, k)
+    }
+
+    /// Same as [`Self::get`] but consults the memory tier first and keeps the loaded value there.
+    /// See [`Self::with_memory_capacity`].
+    pub fn get_shared<K: AsRef<Path>>(&self, k: K) -> Result<Option<Arc<T>>, CacheError> {
+        let k = k.as_ref();
+        self.load_shared(&self.entry_path(k)?, k)
+    }
+
+    /// Same as [`Self::or_insert_with`] but consults the memory tier first and keeps the value there.
+    /// See [`Self::with_memory_capacity`].
+    pub fn or_insert_with_shared<K, F>(&self, k: K, f: F) -> Result<Arc<T>, CacheError>
+        where K: AsRef<Path>, F: FnOnce() -> T
+    {
+        let k = k.as_ref();
+        let path = self.entry_path(k)?;
+        if let Some(v) = self.load_shared(&path, k)? {
+            return Ok(v);
+        }
+        let mut guard = match self.flights.join(&path, self.lock_timeout)? {
+            Joined::Leader(guard) => guard,
+            Joined::Waited(Some(Shared { value: Some(v), .. })) => return Ok(v),
+            Joined::Waited(Some(Shared { bytes, value: None })) =>
+                return Ok(Arc::new(self.codec.decode(&bytes).map_err(CacheError::Decode)?)),
+            Joined::Waited(None) => {
+                if let Some(v) = self.load_shared(&path, k)? {
+                    return Ok(v);
+                }
+                let v = Arc::new(f());
+                let (mut guard, file_lock) = self.lock(&path)?;
+                if let Some(bytes) = self.store_shared(&path, k, &v, file_lock.is_some())? {
+                    guard.publish(Shared { bytes, value: Some(v.clone()) });
+                }
+                return Ok(v);
+            },
+        };
+        let file_lock = self.lock_file(&path)?;
+        if let Some(v) = self.load_shared(&path, k)? {
+            return Ok(v);
+        }
+
+        let v = Arc::new(f());
+        if let Some(bytes) = self.store_shared(&path, k, &v, file_lock.is_some())? {
+            guard.publish(Shared { bytes, value: Some(v.clone()) });
+        }
+        Ok(v)
+    }
+
+    // Same as store but also keeps the value in the memory tier. Returns the encoded value.
+    fn store_shared(&self, path: &Path, k: &Path, v: &Arc<T>, exclusive: bool) -> Result<Option<Arc<[u8]>>, CacheError> {
+        let Some(stored) = self.store(path, k, v, None, exclusive)? else {
+            return Ok(None);
+        };
+        if let Some(memory) = &self.memory {
+            memory.insert(path, v.clone(), stored.expires_at, stored.generation);
+        }
+        Ok(Some(stored.bytes))
+    }
+
+    /// Returns a reader over the cached bytes, or calls `f` to write them on a miss.
+    ///
+    /// The producer writes straight into the temp file of the entry, so values of any size can be cached without
+    /// holding them in memory. Such entries bypass the codec and compression and can only be read with
+    /// [`Self::get_reader`]. An `Err` from the producer, e.g. a failed write, is returned as
+    /// [`TryInsertError::Producer`] and nothing is cached.
+    pub fn or_insert_with_stream<K, F, E>(&self, k: K, f: F) -> Result<EntryReader, TryInsertError<E>>
+        where K: AsRef<Path>, F: FnOnce(&mut dyn Write) -> Result<(), E>
+    {
+        let k = k.as_ref();
+        let path = self.entry_path(k)?;
+        if let Some(r) = self.open_stream(&path, k)? {
+            return Ok(r);
+        }
+        let (_guard, file_lock) = self.lock(&path)?;
+        if let Some(r) = self.open_stream(&path, k)? {
+            return Ok(r);
+        }
+
+        let header = Header::new(
+            SystemTime::now(), self.ttl, codec::STREAM_ID, Compression::None.id(), self.checksum.id(),
+            self.schema_version, key::key_bytes(k),
+        ).to_bytes();
+        let save_path = key::temp_path(&path);
+        if file_lock.is_some() {
+            remove_if_exists(&save_path)?;
+        }
+        let mut file = create_temp(&save_path)?.ok_or_else(|| CacheError::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists, "the entry is being written by another process",
+        )))?;
+        if let Err(e) = self.write_stream(&mut file, &header, f) {
+            drop(file);
+            let _ = fs::remove_file(&save_path);
+            return Err(e);
+        }
+        fs::rename(&save_path, &path).map_err(CacheError::from)?;
+        if let Some(parent) = path.parent() {
+            self.durability.sync_dir(parent).map_err(CacheError::from)?;
+        }
+        self.forget(&path);
+        self.evict()?;
+
+        // The file is still open, so the entry can be read even if it was evicted right away.
+        let start = header.len() as u64;
+        let len = file.metadata().map_err(CacheError::from)?.len() - start;
+        file.seek(SeekFrom::Start(start)).map_err(CacheError::from)?;
+        Ok(EntryReader::new(file, start, len))
+    }
+
+    // Writes the header and the payload produced by f and fills in the checksum.
+    fn write_stream<F, E>(&self, file: &mut File, header: &[u8], f: F) -> Result<(), TryInsertError<E>>
+        where F: FnOnce(&mut dyn Write) -> Result<(), E>
+    {
+        file.write_all(header).map_err(CacheError::from)?;
+        let mut writer = HashingWriter::new(file, self.checksum.hasher());
+        f(&mut writer).map_err(TryInsertError::Producer)?;
+        let checksum = writer.finish().map_err(CacheError::from)?.finish();
+        entry::write_checksum(file, &checksum).map_err(CacheError::from)?;
+        self.durability.sync_file(file).map_err(CacheError::from)?;
+        Ok(())
+    }
+
+    /// Returns a reader over an entry written by [`Self::or_insert_with_stream`], or `None` if the entry is
+    /// missing or expired. The checksum is verified while the payload is read, see [`EntryReader`].
+    pub fn get_reader<K: AsRef<Path>>(&self, k: K) -> Result<Option<EntryReader>, CacheError> {
+        let k = k.as_ref();
+        self.open_stream(&self.entry_path(k)?, k)
+    }
+
+    /// Maps the payload of the entry into memory instead of reading it, e.g. for large lookup tables.
+    /// This is the value as encoded by the codec, or the bytes written by [`Self::or_insert_with_stream`].
+    /// Returns `None` if the entry is missing, expired or corrupt. The checksum is verified before the map is returned.
+    ///
+    /// Compressed entries cannot be mapped and fail with an [`std::io::ErrorKind::Unsupported`] error.
+    #[cfg(feature = "mmap")]
+    pub fn get_mmap<K: AsRef<Path>>(&self, k: K) -> Result<Option<EntryMap>, CacheError> {
+        let k = k.as_ref();
+        self.open_entry(&self.entry_path(k)?, |file, header, start| {
+            if !self.is_live(&header, k, SystemTime::now()) {
+                return Ok(Found::Dead);
+            }
+            if header.codec_id != self.codec.id() && header.codec_id != codec::STREAM_ID {
+                return Ok(Found::Foreign);
+            }
+            if header.compression != Compression::None.id() {
+                return Err(CacheError::Io(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported, "compressed entries cannot be memory mapped",
+                )));
+            }
+            let map = EntryMap::new(&file, start, file.metadata()?.len() - start)?;
+            match Checksum::from_id(header.checksum_kind) {
+                Some(checksum) if checksum.compute(&map) == header.checksum => Ok(Found::Live(map)),
+                _ => Err(CacheError::ChecksumMismatch),
+            }
+        })
+    }
+
+    /// Stores the value, replacing any existing entry.
+    /// If the codec reports the value as [`Uncacheable`], the existing entry is removed instead.
+    /// Waits while another thread or process computes the same key, see [`Self::with_lock_timeout`].
+    pub fn insert<K: AsRef<Path>>(&self, k: K, v: &T) -> Result<(), CacheError> {
+        let k = k.as_ref();
+        let path = self.entry_path(k)?;
+        let (mut guard, file_lock) = self.lock(&path)?;
+        match self.store(&path, k, v, None, file_lock.is_some())? {
+            Some(stored) => guard.publish(Shared { bytes: stored.bytes, value: None }),
+            None => {
+                remove_if_exists(&path)?;
+                self.forget(&path);
+            },
+        }
         Ok(())
     }
+
+    /// Removes the entry. Returns false if it did not exist.
+    pub fn remove<K: AsRef<Path>>(&self, k: K) -> Result<bool, CacheError> {
+        let path = self.entry_path(k)?;
+        let removed = remove_if_exists(&path)?;
+        self.forget(&path);
+        Ok(removed)
+    }
+
+    /// Returns true if a live entry exists for the key. The value itself is not decoded.
+    pub fn contains<K: AsRef<Path>>(&self, k: K) -> Result<bool, CacheError> {
+        let k = k.as_ref();
+        match Header::read_from(&self.entry_path(k)?) {
+            Ok(header) => Ok(self.is_live(&header, k, SystemTime::now())
+                && header.codec_id == self.codec.id()
+                && compression::is_supported(header.compression)),
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::InvalidData => Ok(false),
+                _ => Err(e.into()),
+            }
+        }
+    }
+
+    // Writes the entry and applies the size limits. Returns None if the value is not cacheable.
+    // `exclusive` tells that the file lock of the entry is held, so no other writer can be active.
+    fn store(
+        &self, path: &Path, k: &Path, v: &T, ttl: Option<Duration>, exclusive: bool,
+    ) -> Result<Option<Stored>, CacheError> {
+        let header = Header::new(
+            SystemTime::now(), ttl.or(self.ttl), self.codec.id(), self.compression.id(), self.checksum.id(),
+            self.schema_version, key::key_bytes(k),
+        );
+        let mut bin = header.to_bytes();
+        let payload_offset = bin.len();
+        let encoded = if self.compression == Compression::None {
+            self.codec.encode(v, &mut bin).map(|_| Arc::from(&bin[payload_offset..]))
+        } else {
+            let mut payload = Vec::new();
+            self.codec.encode(v, &mut payload)
+                .and_then(|_| Ok(self.compression.compress_into(&payload, &mut bin)?))
+                .map(|_| Arc::from(payload))
+        };
+        let encoded = match encoded {
+            Ok(encoded) => encoded,
+            Err(e) => return if codec::is_uncacheable(&e) { Ok(None) } else { Err(CacheError::Encode(e)) },
+        };
+        let checksum = self.checksum.compute(&bin[payload_offset..]);
+        entry::set_checksum(&mut bin, &checksum);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if exclusive {
+            // A temp file can only have been left by a writer that crashed before renaming it.
+            remove_if_exists(&key::temp_path(path))?;
+        }
+        LocalFileCache::<T>::save_to(path, &bin, self.durability)?;
+        self.forget(path);
+        let generation = self.memory.as_ref().map_or(0, MemoryTier::generation);
+        self.evict()?;
+        Ok(Some(Stored { expires_at: header.expires_at, bytes: encoded, generation }))
+    }
+
+    // Applies the size limits. Evicted entries are dropped from the memory tier as well.
+    fn evict(&self) -> Result<(), CacheError> {
+        if self.max_bytes.is_none() && self.max_entries.is_none() {
+            return Ok(());
+        }
+        for path in eviction::evict(&self.dir, self.max_bytes, self.max_entries, self.eviction_policy.as_ref())? {
+            self.forget(&path);
+        }
+        Ok(())
+    }
+
+    // Drops the value of the entry from the memory tier.
+    fn forget(&self, path: &Path) {
+        if let Some(memory) = &self.memory {
+            memory.remove(path);
+        }
+    }
+
+    /// Stores an application defined score in the entry, available to eviction policies as [`EntryInfo::score`].
+    /// Returns false if the entry does not exist.
+    pub fn set_score<K: AsRef<Path>>(&self, k: K, score: i64) -> Result<bool, CacheError> {
+        match entry::set_score(&self.entry_path(k)?, score) {
+            Ok(_) => Ok(true),
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(false),
+                _ => Err(e.into()),
+            }
+        }
+    }
+
+    /// Lists the entries currently stored in the cache. Files that are not valid entries are skipped.
+    pub fn entries(&self) -> Result<Vec<EntryInfo>, CacheError> {
+        let mut entries = Vec::new();
+        eviction::scan(&self.dir, &mut entries, &mut Vec::new())?;
+        Ok(entries)
+    }
+
+    fn entry_path<K: AsRef<Path>>(&self, k: K) -> Result<PathBuf, CacheError> {
+        let mut buf = PathBuf::new();
+        buf.push(&self.dir);
+        buf.push(self.key_mode.relative_path(k.as_ref())?);
+        Ok(buf)
+    }
+
+    // False if the entry is expired or was written for another key or schema version.
+    // The codec is checked separately because entries of other codecs are left alone.
+    fn is_live(&self, header: &Header, k: &Path, now: SystemTime) -> bool {
+        !header.is_expired(now)
+            && header.key == key::key_bytes(k)
+            && header.schema_version == self.schema_version
+    }
+
+    // Returns None if the entry does not exist, is not live or is corrupt.
+    // Such entries are removed.
+    fn load(&self, path: &Path, k: &Path) -> Result<Option<T>, CacheError> {
+        Ok(self.load_entry(path, k)?.map(|(v, _)| v))
+    }
+
+    fn load_shared(&self, path: &Path, k: &Path) -> Result<Option<Arc<T>>, CacheError> {
+        let Some(memory) = &self.memory else {
+            return Ok(self.load(path, k)?.map(Arc::new));
+        };
+        if let Some(v) = memory.get(path, SystemTime::now()) {
+            return Ok(Some(v));
+        }
+        let generation = memory.generation();
+        Ok(self.load_entry(path, k)?.map(|(v, expires_at)| {
+            let v = Arc::new(v);
+            memory.insert(path, v.clone(), expires_at, generation);
+            v
+        }))
+    }
+
+    // Same as load for entries written by or_insert_with_stream. The payload is not read into memory, so its checksum
+    // is verified by the reader.
+    fn open_stream(&self, path: &Path, k: &Path) -> Result<Option<EntryReader>, CacheError> {
+        self.open_entry(path, |file, header, start| {
+            if !self.is_live(&header, k, SystemTime::now()) {
+                return Ok(Found::Dead);
+            }
+            if header.codec_id != codec::STREAM_ID {
+                return Ok(Found::Foreign);
+            }
+            let hasher = Checksum::from_id(header.checksum_kind).ok_or(CacheError::ChecksumMismatch)?.hasher();
+            let len = file.metadata()?.len() - start;
+            Ok(Found::Live(EntryReader::new(file, start, len).verified(hasher, header.checksum, path.to_owned())))
+        })
+    }
+
+    // Opens the entry without reading its payload. `read` gets the file positioned at the payload, its header and the
+    // offset of the payload. Dead and corrupt entries are removed.
+    fn open_entry<R, F>(&self, path: &Path, read: F) -> Result<Option<R>, CacheError>
+        where F: FnOnce(File, Header, u64) -> Result<Found<R>, CacheError>
+    {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => return Ok(None),
+                _ => return Err(e.into()),
+            },
+        };
+        let opened = Header::read(&mut file).and_then(|(header, offset)| read(file, header, offset as u64));
+        match opened {
+            Ok(Found::Live(r)) => {
+                // Failing to record the access only affects eviction order.
+                let _ = entry::touch(path, SystemTime::now());
+                return Ok(Some(r));
+            },
+            Ok(Found::Foreign) => return Ok(None),
+            Ok(Found::Dead) => {},
+            // Written by a newer version of this crate sharing the directory.
+            Err(CacheError::UnsupportedFormat { version, flags }) if entry::FORMAT_VERSION < version || flags != 0 =>
+                return Ok(None),
+            Err(e @ (CacheError::Io(_) | CacheError::DiskFull(_))) => return Err(e),
+            Err(e) => if let Some(hook) = &self.on_corrupt {
+                hook(path, &e);
+            }
+        }
+        remove_if_exists(path)?;
+        Ok(None)
+    }
+
+    // Same as load but also returns the expiry of the entry.
+    fn load_entry(&self, path: &Path, k: &Path) -> Result<Option<(T, Option<SystemTime>)>, CacheError> {
+        self.open_entry(path, |mut file, header, start| {
+            if !self.is_live(&header, k, SystemTime::now()) {
+                return Ok(Found::Dead);
+            }
+            // E.g. written by or_insert_with_stream or by an instance with another codec.
+            if header.codec_id != self.codec.id() || !compression::is_supported(header.compression) {
+                return Ok(Found::Foreign);
+            }
+            let mut stored = Vec::with_capacity(file.metadata()?.len().saturating_sub(start) as usize);
+            file.read_to_end(&mut stored)?;
+            match Checksum::from_id(header.checksum_kind) {
+                Some(checksum) if checksum.compute(&stored) == header.checksum => {},
+                _ => return Err(CacheError::ChecksumMismatch),
+            }
+            compression::decompress(header.compression, &stored)
+                .and_then(|payload| self.codec.decode(&payload))
+                .map(|v| Found::Live((v, header.expires_at)))
+                .map_err(CacheError::Decode)
+        })
+    }
+}
+
+// An entry written by store.
+struct Stored {
+    expires_at: Option<SystemTime>,
+    // The value as encoded by the codec, before compression.
+    bytes: Arc<[u8]>,
+    // Generation of the memory tier once the entry was written. The value is not kept in memory if the entry was
+    // evicted or replaced since.
+    generation: u64,
+}
+
+// The value stored by the leader of a flight, handed to the threads that waited for it instead of reading the entry.
+// They would miss an entry that expired right away.
+struct Shared<T> {
+    bytes: Arc<[u8]>,
+    value: Option<Arc<T>>,
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self { bytes: self.bytes.clone(), value: self.value.clone() }
+    }
+}
+
+// What was found in an entry file.
+enum Found<R> {
+    Live(R),
+    // Expired or written for another key or schema version. Such entries are removed.
+    Dead,
+    // A valid entry this instance cannot read, e.g. written with another codec or compressed with an algorithm not
+    // enabled in this build. It is treated as a miss but left alone.
+    Foreign,
+}
+
+// Creates the temp file of an entry. None if another writer is using it.
+// A temp file not modified for STALE_TEMP_AGE was left by a crashed writer. It is removed and the creation is retried.
+fn create_temp(save_path: &Path) -> std::io::Result<Option<File>> {
+    let create = || OpenOptions::new().read(true).write(true).create_new(true).open(save_path);
+    match create() {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            if !is_stale(save_path)? {
+                return Ok(None);
+            }
+            remove_if_exists(save_path)?;
+            match create() {
+                Ok(file) => Ok(Some(file)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(None),
+                Err(e) => Err(e),
+            }
+        },
+        Err(e) => Err(e),
+    }
+}
+
+// Temp files not modified for this long are considered abandoned.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(10 * 60);
+
+fn is_stale(temp_path: &Path) -> std::io::Result<bool> {
+    match fs::metadata(temp_path).and_then(|m| m.modified()) {
+        Ok(modified) => Ok(modified.elapsed().is_ok_and(|age| STALE_TEMP_AGE <= age)),
+        Err(e) => match e.kind() {
+            // Renamed by its writer in the meantime.
+            std::io::ErrorKind::NotFound => Ok(false),
+            _ => Err(e),
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<bool> {
+    match fs::remove_file(path) {
+        Ok(_) => Ok(true),
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(false),
+            _ => Err(e),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Instant;
     use tempfile::tempdir;
-    use rand;
+
+    pub(crate) fn string_cache(dir: &Path) -> LocalFileCacheBuilder {
+        LocalFileCacheBuilder::new("test").base_dir(dir)
+    }
+
+    pub(crate) fn string_codec() -> FnCodec<String> {
+        FnCodec::new(
+            Box::new(|s| Some(s.as_bytes().to_vec())),
+            Box::new(|data| Ok(String::from_utf8(data.to_vec())?)),
+        )
+    }
 
     #[test]
+    #[allow(unused_assignments)]
     fn can_cache() {
-        let rand: u128 = rand::random();
-        let path = format!("local_file_cache_test-{}", rand);
-
-        let test_result = std::panic::catch_unwind(|| {
-            let cache = LocalFileCache::<String>::new(&path,
-                Box::new(|bin| {
-                    Some(vec![bin.parse::<u8>().unwrap()])
-                }),
-                Box::new(|data| {
-                    format!("{}", data[0])
-                }),
-            ).unwrap();
-            cache.flush().unwrap();
-            let mut called = false;
-            let ret = cache.or_insert_with("data0", || {
-                called = true;
-                "123".to_owned()
-            }).unwrap();
-            
-            assert_eq!(ret, "123".to_owned());
-            assert!(called);
-            
-            called = false;
-            let ret = cache.or_insert_with("data0", || {
-                "234".to_owned()
-            }).unwrap();
-            
-            assert_eq!(ret, "123".to_owned());
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).build_with::<String>(
+            Box::new(|bin| {
+                Some(vec![bin.parse::<u8>().unwrap()])
+            }),
+            Box::new(|data| {
+                Ok(format!("{}", data[0]))
+            }),
+        ).unwrap();
+        cache.flush().unwrap();
+        let mut called = false;
+        let ret = cache.or_insert_with("data0", || {
+            called = true;
+            "123".to_owned()
+        }).unwrap();
+        
+        assert_eq!(ret, "123".to_owned());
+        assert!(called);
+        
+        called = false;
+        let ret = cache.or_insert_with("data0", || {
+            "234".to_owned()
+        }).unwrap();
+        
+        assert_eq!(ret, "123".to_owned());
+    }
+
+    #[test]
+    fn try_or_insert_with_does_not_cache_error() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).build(string_codec()).unwrap();
+
+        let ret = cache.try_or_insert_with("data0", || Err("unavailable"));
+        assert!(matches!(ret, Err(TryInsertError::Producer("unavailable"))));
+
+        let ret = cache.try_or_insert_with("data0", || Ok::<_, &str>("abc".to_owned())).unwrap();
+        assert_eq!(ret, "abc");
+
+        let ret = cache.try_or_insert_with("data0", || Err("unavailable")).unwrap();
+        assert_eq!(ret, "abc");
+    }
+
+    #[test]
+    fn corrupt_entry_is_regenerated() {
+        let dir = tempdir().unwrap();
+        let reported = Arc::new(AtomicBool::new(false));
+        let reported_in_hook = reported.clone();
+        let cache = string_cache(dir.path())
+            .on_corrupt(move |_, _| reported_in_hook.store(true, Ordering::SeqCst))
+            .build(string_codec()).unwrap();
+
+        fs::create_dir_all(&cache.dir).unwrap();
+        let mut garbage = Header::new(SystemTime::now(), None, 0, 0, Checksum::None.id(), 0, b"data0").to_bytes();
+        garbage.extend_from_slice(&[0xffu8, 0xfe]);
+        fs::write(cache.dir.join("data0"), garbage).unwrap();
+
+        let ret = cache.or_insert_with("data0", || "abc".to_owned()).unwrap();
+        assert_eq!(ret, "abc");
+        assert!(reported.load(Ordering::SeqCst));
+        let ret = cache.or_insert_with("data0", || "def".to_owned()).unwrap();
+        assert_eq!(ret, "abc");
+    }
+
+    #[test]
+    fn entries_this_build_cannot_read_are_kept() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path())
+            .on_corrupt(|_, e| panic!("reported as corrupt: {}", e))
+            .build(string_codec()).unwrap();
+
+        // Written by a build with a compression feature this build lacks.
+        fs::create_dir_all(&cache.dir).unwrap();
+        let mut bin = Header::new(SystemTime::now(), None, 0, 200, Checksum::Crc32.id(), 0, b"data0").to_bytes();
+        let payload = b"compressed";
+        entry::set_checksum(&mut bin, &Checksum::Crc32.compute(payload));
+        bin.extend_from_slice(payload);
+        fs::write(cache.dir.join("data0"), &bin).unwrap();
+
+        assert_eq!(cache.get("data0").unwrap(), None);
+        assert!(!cache.contains("data0").unwrap());
+        assert_eq!(read_all_bytes(cache.dir.join("data0")), bin);
+
+        // Written by a newer version of this crate.
+        cache.insert("data1", &"abc".to_owned()).unwrap();
+        cache.insert("data2", &"abc".to_owned()).unwrap();
+        let mut newer = read_all_bytes(cache.dir.join("data1"));
+        newer[4..6].copy_from_slice(&(entry::FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(cache.dir.join("data1"), &newer).unwrap();
+        let mut flagged = read_all_bytes(cache.dir.join("data2"));
+        flagged[6] = 1;
+        fs::write(cache.dir.join("data2"), &flagged).unwrap();
+
+        assert_eq!(cache.get("data1").unwrap(), None);
+        assert!(cache.get_reader("data2").unwrap().is_none());
+        assert_eq!(read_all_bytes(cache.dir.join("data1")), newer);
+        assert_eq!(read_all_bytes(cache.dir.join("data2")), flagged);
+    }
+
+    #[test]
+    fn expired_entry_is_recomputed() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).ttl(Duration::ZERO).build(string_codec()).unwrap();
+
+        let ret = cache.or_insert_with("data0", || "abc".to_owned()).unwrap();
+        assert_eq!(ret, "abc");
+        let ret = cache.or_insert_with("data0", || "def".to_owned()).unwrap();
+        assert_eq!(ret, "def");
+
+        let ret = cache.or_insert_with_ttl("data0", || ("ghi".to_owned(), Some(Duration::from_secs(3600)))).unwrap();
+        assert_eq!(ret, "ghi");
+        let ret = cache.or_insert_with("data0", || "jkl".to_owned()).unwrap();
+        assert_eq!(ret, "ghi");
+
+        // Lifetimes beyond what SystemTime can represent mean the entry never expires.
+        let ret = cache.or_insert_with_ttl("data1", || ("abc".to_owned(), Some(Duration::MAX))).unwrap();
+        assert_eq!(ret, "abc");
+        assert_eq!(cache.get("data1").unwrap(), Some("abc".to_owned()));
+        let cache = cache.with_ttl(Duration::MAX);
+        assert_eq!(cache.or_insert_with("data2", || "def".to_owned()).unwrap(), "def");
+        assert_eq!(cache.get("data2").unwrap(), Some("def".to_owned()));
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let dir = tempdir().unwrap();
+        let entry_len = (entry::HEADER_LEN + "data0".len() + 10) as u64;
+        let cache = string_cache(dir.path()).max_bytes(entry_len * 2).build(string_codec()).unwrap();
+
+        cache.or_insert_with("data0", || "0000000000".to_owned()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        cache.or_insert_with("data1", || "1111111111".to_owned()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        // Reading data0 makes data1 the least recently used entry.
+        cache.or_insert_with("data0", || unreachable!()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        cache.or_insert_with("data2", || "2222222222".to_owned()).unwrap();
+
+        assert!(cache.dir.join("data0").exists());
+        assert!(!cache.dir.join("data1").exists());
+        assert!(cache.dir.join("data2").exists());
+    }
+
+    #[test]
+    fn custom_eviction_policy_uses_score() {
+        struct LowestScoreFirst;
+
+        impl EvictionPolicy for LowestScoreFirst {
+            fn order(&self, entries: &mut [EntryInfo]) {
+                entries.sort_by_key(|e| e.score);
+            }
+        }
+
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path())
+            .max_entries(2).eviction_policy(LowestScoreFirst)
+            .build(string_codec()).unwrap();
+
+        cache.or_insert_with("data0", || "0".to_owned()).unwrap();
+        cache.or_insert_with("data1", || "1".to_owned()).unwrap();
+        assert!(cache.set_score("data0", -10).unwrap());
+        assert!(cache.set_score("data1", -20).unwrap());
+        assert!(!cache.set_score("missing", 1).unwrap());
+        // data2 is written with the default score 0.
+        cache.or_insert_with("data2", || "2".to_owned()).unwrap();
+
+        assert!(cache.dir.join("data0").exists());
+        assert!(!cache.dir.join("data1").exists());
+        assert!(cache.dir.join("data2").exists());
+    }
+
+    #[test]
+    fn hashed_keys_stay_inside_cache_dir() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).key_mode(KeyMode::Hashed).build(string_codec()).unwrap();
+
+        let long_key = "k".repeat(1000);
+        for k in ["../../escape", "a/b/c", long_key.as_str()] {
+            let ret = cache.or_insert_with(k, || k.to_owned()).unwrap();
+            assert_eq!(ret, k);
+            let ret = cache.or_insert_with(k, || unreachable!()).unwrap();
+            assert_eq!(ret, k);
+        }
+
+        let mut keys: Vec<Vec<u8>> = cache.entries().unwrap().into_iter().map(|e| e.key).collect();
+        keys.sort();
+        assert_eq!(keys, vec![b"../../escape".to_vec(), b"a/b/c".to_vec(), long_key.into_bytes()]);
+        assert_eq!(fs::read_dir(&cache.dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).build(string_codec()).unwrap();
+
+        for k in ["../escape", "/tmp/escape", ""] {
+            let ret = cache.or_insert_with(k, || unreachable!());
+            assert!(matches!(ret, Err(CacheError::InvalidKey { .. })), "{}", k);
+        }
+
+        let ret = cache.or_insert_with("a/b", || "ab".to_owned()).unwrap();
+        assert_eq!(ret, "ab");
+        assert!(cache.dir.join("a").join("b").exists());
+    }
+
+    #[test]
+    fn get_insert_remove_contains() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).build(string_codec()).unwrap();
+
+        assert_eq!(cache.get("data0").unwrap(), None);
+        assert!(!cache.contains("data0").unwrap());
+
+        cache.insert("data0", &"abc".to_owned()).unwrap();
+        assert!(cache.contains("data0").unwrap());
+        assert_eq!(cache.get("data0").unwrap(), Some("abc".to_owned()));
+
+        cache.insert("data0", &"def".to_owned()).unwrap();
+        assert_eq!(cache.or_insert_with("data0", || unreachable!()).unwrap(), "def");
+
+        assert!(cache.remove("data0").unwrap());
+        assert!(!cache.remove("data0").unwrap());
+        assert!(!cache.contains("data0").unwrap());
+        assert_eq!(cache.get("data0").unwrap(), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn can_cache_with_serde() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Item {
+            id: u32,
+            tags: Vec<String>,
+        }
+
+        let dir = tempdir().unwrap();
+        let cache = LocalFileCacheBuilder::new("test").base_dir(dir.path()).build_serde::<Item, formats::MessagePack>().unwrap();
+        let item = || Item { id: 1, tags: vec!["a".to_owned()] };
+
+        assert_eq!(cache.or_insert_with("data0", item).unwrap(), item());
+        assert_eq!(cache.get("data0").unwrap(), Some(item()));
+    }
+
+    #[test]
+    fn can_cache_with_custom_codec() {
+        struct U32Codec(u32);
+
+        impl Codec<u32> for U32Codec {
+            fn id(&self) -> u32 {
+                self.0
+            }
+
+            fn encode(&self, value: &u32, w: &mut dyn Write) -> Result<(), codec::CodecError> {
+                if *value == 0 {
+                    return Err("zero is not allowed".into());
+                }
+                Ok(w.write_all(&value.to_le_bytes())?)
+            }
+
+            fn decode(&self, bytes: &[u8]) -> Result<u32, codec::CodecError> {
+                Ok(u32::from_le_bytes(bytes.try_into()?))
+            }
+        }
+
+        let dir = tempdir().unwrap();
+        let cache = LocalFileCacheBuilder::new("test").base_dir(dir.path()).build(U32Codec(1000)).unwrap();
+        assert_eq!(cache.or_insert_with("data0", || 123).unwrap(), 123);
+        assert_eq!(cache.get("data0").unwrap(), Some(123));
+        assert!(matches!(cache.insert("data0", &0), Err(CacheError::Encode(_))));
+
+        // Entries written by another codec are misses.
+        let cache = LocalFileCacheBuilder::new("test").base_dir(dir.path()).build(U32Codec(1001)).unwrap();
+        assert_eq!(cache.get("data0").unwrap(), None);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn can_cache_compressed() {
+        let dir = tempdir().unwrap();
+        let new_cache = || string_cache(dir.path()).build(string_codec()).unwrap();
+        let text = "abc".repeat(1000);
+
+        new_cache().insert("plain", &text).unwrap();
+
+        let cache = new_cache().with_compression(Compression::Zstd { level: 3 });
+        cache.insert("compressed", &text).unwrap();
+        assert!(fs::metadata(cache.dir.join("compressed")).unwrap().len() < 1000);
+        assert_eq!(cache.get("compressed").unwrap(), Some(text.clone()));
+        // Entries written before enabling compression are still readable.
+        assert_eq!(cache.get("plain").unwrap(), Some(text.clone()));
+
+        assert_eq!(new_cache().get("compressed").unwrap(), Some(text));
+    }
+
+    #[test]
+    fn checksum_mismatch_is_reported() {
+        let dir = tempdir().unwrap();
+        let reported = Arc::new(AtomicBool::new(false));
+        let reported_in_hook = reported.clone();
+        let cache = string_cache(dir.path()).checksum(Checksum::Sha256).on_corrupt(move |_, e| {
+            assert!(matches!(e, CacheError::ChecksumMismatch));
+            reported_in_hook.store(true, Ordering::SeqCst);
+        }).build(string_codec()).unwrap();
+
+        cache.insert("data0", &"abc".to_owned()).unwrap();
+        let file = cache.dir.join("data0");
+        let mut bin = read_all_bytes(&file);
+        let last = bin.len() - 1;
+        bin[last] = b'd';
+        fs::write(&file, bin).unwrap();
+
+        assert_eq!(cache.get("data0").unwrap(), None);
+        assert!(reported.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn entries_of_other_schema_versions_are_ignored() {
+        let dir = tempdir().unwrap();
+        let new_cache = |version| string_cache(dir.path()).schema_version(version).build(string_codec()).unwrap();
+
+        let v1 = new_cache(1);
+        v1.insert("data0", &"abc".to_owned()).unwrap();
+        assert!(v1.contains("data0").unwrap());
+
+        let v2 = new_cache(2);
+        assert!(!v2.contains("data0").unwrap());
+        assert_eq!(v2.get("data0").unwrap(), None);
+        // The old entry is removed once it has been accessed.
+        assert!(!v2.dir.join("data0").exists());
+        assert_eq!(v2.or_insert_with("data0", || "def".to_owned()).unwrap(), "def");
+    }
+
+    #[test]
+    fn can_be_shared_between_threads() {
+        fn assert_send_sync<S: Send + Sync>() {}
+        assert_send_sync::<LocalFileCache<String>>();
+
+        let dir = tempdir().unwrap();
+        let cache = Arc::new(string_cache(dir.path()).max_entries(5).build(string_codec()).unwrap());
+
+        let handles: Vec<_> = (0..8).map(|t| {
+            let cache = cache.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    let key = format!("data{}", (i + t) % 10);
+                    let ret = cache.or_insert_with(&key, || format!("value of {}", key)).unwrap();
+                    assert_eq!(ret, format!("value of {}", key));
+                    if i % 7 == 0 {
+                        cache.remove(&key).unwrap();
+                    }
+                    if let Some(v) = cache.get(&key).unwrap() {
+                        assert_eq!(v, format!("value of {}", key));
+                    }
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!(cache.entries().unwrap().len() <= 5);
+    }
+
+    #[test]
+    fn concurrent_misses_call_the_producer_once() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).build(string_codec()).unwrap();
+        let calls = AtomicUsize::new(0);
+        let start = Barrier::new(8);
+
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    start.wait();
+                    let ret = cache.or_insert_with("data0", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(50));
+                        "abc".to_owned()
+                    }).unwrap();
+                    assert_eq!(ret, "abc");
+                });
+            }
         });
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn waiters_share_the_result_of_the_leader() {
+        let dir = tempdir().unwrap();
+        let calls = AtomicUsize::new(0);
+        let race = |cache: &LocalFileCache<String>| {
+            let start = Barrier::new(8);
+            let started = Instant::now();
+            std::thread::scope(|s| {
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        start.wait();
+                        let ret = cache.or_insert_with("data0", || {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            std::thread::sleep(Duration::from_millis(100));
+                            "abc".to_owned()
+                        }).unwrap();
+                        assert_eq!(ret, "abc");
+                    });
+                }
+            });
+            (calls.swap(0, Ordering::SeqCst), started.elapsed())
+        };
 
-        LocalFileCache::<()>::invalidate(&path);
+        // The entry expires right away, so it cannot be read back.
+        let expiring = string_cache(dir.path()).ttl(Duration::ZERO).build(string_codec()).unwrap();
+        assert_eq!(race(&expiring).0, 1);
 
-        if let Err(e) = test_result {
-            std::panic::resume_unwind(e);
+        // Waiters do not run their producers one after another when nothing was cached.
+        let uncacheable = string_cache(dir.path()).build_with::<String>(
+            Box::new(|_| None),
+            Box::new(|data| Ok(String::from_utf8(data.to_vec())?)),
+        ).unwrap();
+        let (calls, elapsed) = race(&uncacheable);
+        assert_eq!(calls, 8);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+
+        let impatient = string_cache(dir.path()).lock_timeout(Duration::from_millis(20)).build(string_codec()).unwrap();
+        let _leader = impatient.flights.join(&impatient.entry_path("data1").unwrap(), Duration::ZERO).unwrap();
+        let err = impatient.or_insert_with("data1", || "abc".to_owned()).unwrap_err();
+        assert!(matches!(err, CacheError::LockTimeout { .. }));
+    }
+
+    #[test]
+    fn waiters_take_the_locks_before_writing() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).build(string_codec()).unwrap();
+        let path = cache.entry_path("data0").unwrap();
+        let (leading, producing) = (Barrier::new(2), Barrier::new(2));
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let err = cache.try_or_insert_with("data0", || {
+                    leading.wait();
+                    std::thread::sleep(Duration::from_millis(50));
+                    Err("offline")
+                }).unwrap_err();
+                assert!(matches!(err, TryInsertError::Producer("offline")));
+            });
+            leading.wait();
+            let waiter = s.spawn(|| cache.try_or_insert_with("data0", || {
+                producing.wait();
+                producing.wait();
+                Ok::<_, Infallible>("waiter".to_owned())
+            }).unwrap());
+
+            // The waiter runs its producer once the leader failed, but waits for a writer holding the locks.
+            producing.wait();
+            let locks = cache.lock(&path).unwrap();
+            producing.wait();
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!path.exists());
+            drop(locks);
+            assert_eq!(waiter.join().unwrap(), "waiter");
+        });
+        assert_eq!(cache.get("data0").unwrap(), Some("waiter".to_owned()));
+    }
+
+    #[test]
+    fn separate_instances_wait_for_each_other() {
+        let dir = tempdir().unwrap();
+        let new_cache = || string_cache(dir.path()).build(string_codec()).unwrap();
+        let (first, second) = (new_cache(), new_cache());
+        let started = Barrier::new(2);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                first.or_insert_with("data0", || {
+                    started.wait();
+                    std::thread::sleep(Duration::from_millis(100));
+                    "first".to_owned()
+                }).unwrap();
+            });
+            started.wait();
+            assert_eq!(second.or_insert_with("data0", || "second".to_owned()).unwrap(), "first");
+        });
+
+        let impatient = new_cache().with_lock_timeout(Duration::from_millis(20));
+        let _held = flight::lock_file(&key::lock_path(&impatient.dir.join("data1")), Duration::ZERO).unwrap();
+        let err = impatient.or_insert_with("data1", || "abc".to_owned()).unwrap_err();
+        assert!(matches!(err, CacheError::LockTimeout { .. }));
+    }
+
+    #[test]
+    fn temp_file_of_crashed_writer_is_recovered() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).build(string_codec()).unwrap();
+
+        // A writer died after creating the temp file and before renaming it.
+        fs::create_dir_all(&cache.dir).unwrap();
+        fs::write(key::temp_path(&cache.dir.join("data0")), b"partial").unwrap();
+
+        assert_eq!(cache.or_insert_with("data0", || "abc".to_owned()).unwrap(), "abc");
+        assert_eq!(cache.or_insert_with("data0", || unreachable!()).unwrap(), "abc");
+        assert!(!key::temp_path(&cache.dir.join("data0")).exists());
+
+        // Without holding the lock, only old temp files are considered abandoned.
+        let path = dir.path().join("data1");
+        let temp = File::create(key::temp_path(&path)).unwrap();
+        LocalFileCache::<()>::save_to(&path, b"abc", Durability::None).unwrap();
+        assert!(!path.exists());
+        temp.set_modified(SystemTime::now() - STALE_TEMP_AGE).unwrap();
+        LocalFileCache::<()>::save_to(&path, b"abc", Durability::None).unwrap();
+        assert_eq!(read_all_bytes(&path), b"abc");
+    }
+
+    #[test]
+    fn memory_tier_is_kept_coherent() {
+        let dir = tempdir().unwrap();
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let decoded_in_codec = decoded.clone();
+        let cache = string_cache(dir.path()).memory_capacity(2).build_with::<String>(
+            Box::new(|s| Some(s.as_bytes().to_vec())),
+            Box::new(move |data| {
+                decoded_in_codec.fetch_add(1, Ordering::SeqCst);
+                Ok(String::from_utf8(data.to_vec())?)
+            }),
+        ).unwrap();
+
+        assert_eq!(*cache.or_insert_with_shared("data0", || "abc".to_owned()).unwrap(), "abc");
+        assert_eq!(*cache.or_insert_with_shared("data0", || unreachable!()).unwrap(), "abc");
+        assert_eq!(*cache.get_shared("data0").unwrap().unwrap(), "abc");
+        assert_eq!(decoded.load(Ordering::SeqCst), 0);
+
+        cache.insert("data0", &"def".to_owned()).unwrap();
+        assert_eq!(*cache.get_shared("data0").unwrap().unwrap(), "def");
+        assert_eq!(*cache.get_shared("data0").unwrap().unwrap(), "def");
+        assert_eq!(decoded.load(Ordering::SeqCst), 1);
+
+        assert!(cache.remove("data0").unwrap());
+        assert_eq!(cache.get_shared("data0").unwrap(), None);
+
+        cache.or_insert_with_shared("data1", || "ghi".to_owned()).unwrap();
+        cache.flush().unwrap();
+        assert_eq!(cache.get_shared("data1").unwrap(), None);
+    }
+
+    #[test]
+    fn memory_tier_follows_the_entry_files() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path())
+            .max_entries(1)
+            .memory_capacity(2)
+            .build(string_codec())
+            .unwrap()
+            .with_memory_weight(3, |s| s.len());
+
+        cache.or_insert_with_shared("data0", || "abc".to_owned()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        // Evicted from disk, so dropped from memory as well.
+        cache.or_insert_with_shared("data1", || "def".to_owned()).unwrap();
+        assert_eq!(cache.get_shared("data0").unwrap(), None);
+
+        cache.or_insert_with_shared("data2", || "heavy".to_owned()).unwrap();
+        fs::write(cache.entry_path("data2").unwrap(), b"garbage").unwrap();
+        assert_eq!(cache.get_shared("data2").unwrap(), None);
+    }
+
+    #[test]
+    fn memory_hits_are_recorded_lazily() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).memory_capacity(1).build(string_codec()).unwrap();
+        let hits = |k: &str| Header::read_from(&cache.entry_path(k).unwrap()).unwrap().hits;
+
+        cache.or_insert_with_shared("data0", || "abc".to_owned()).unwrap();
+        for _ in 0..3 {
+            cache.get_shared("data0").unwrap().unwrap();
         }
+        assert_eq!(hits("data0"), 0);
+
+        // Recorded once the value is dropped from memory.
+        cache.or_insert_with_shared("data1", || "def".to_owned()).unwrap();
+        assert_eq!(hits("data0"), 3);
+
+        cache.get_shared("data1").unwrap().unwrap();
+        let path = cache.entry_path("data1").unwrap();
+        drop(cache);
+        assert_eq!(Header::read_from(&path).unwrap().hits, 1);
+    }
+
+    #[test]
+    fn large_entries_can_be_streamed() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).build(string_codec()).unwrap();
+        let chunk: Vec<u8> = (0..=255).collect();
+
+        let mut reader = cache.or_insert_with_stream("data0", |w| {
+            for _ in 0..1024 {
+                w.write_all(&chunk)?;
+            }
+            Ok::<_, std::io::Error>(())
+        }).unwrap();
+        assert_eq!(reader.len(), 256 * 1024);
+        let mut bin = Vec::new();
+        reader.read_to_end(&mut bin).unwrap();
+        assert_eq!(bin, chunk.repeat(1024));
+
+        let mut reader = cache.or_insert_with_stream("data0", |_| -> Result<(), std::io::Error> { unreachable!() }).unwrap();
+        reader.seek(SeekFrom::End(-2)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, vec![254, 255]);
+
+        let err = cache.or_insert_with_stream("data1", |w| {
+            w.write_all(b"partial")?;
+            Err(std::io::Error::other("producer failed"))
+        }).unwrap_err();
+        assert!(matches!(err, TryInsertError::Producer(_)));
+        assert!(cache.get_reader("data1").unwrap().is_none());
+        assert!(!key::temp_path(&cache.dir.join("data1")).exists());
+
+        // Entries of the codec and streamed entries do not replace each other on reads.
+        cache.insert("data2", &"abc".to_owned()).unwrap();
+        assert!(cache.get_reader("data2").unwrap().is_none());
+        assert_eq!(cache.get("data0").unwrap(), None);
+        assert_eq!(cache.get("data2").unwrap(), Some("abc".to_owned()));
+        assert_eq!(cache.get_reader("data0").unwrap().unwrap().len(), 256 * 1024);
+
+        let file = cache.dir.join("data0");
+        let mut bin = read_all_bytes(&file);
+        let last = bin.len() - 1;
+        bin[last] ^= 1;
+        fs::write(&file, bin).unwrap();
+        let err = cache.get_reader("data0").unwrap().unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(cache.get_reader("data0").unwrap().is_none());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn entries_can_be_memory_mapped() {
+        let dir = tempdir().unwrap();
+        let cache = string_cache(dir.path()).build(string_codec()).unwrap();
+
+        cache.insert("data0", &"abc".to_owned()).unwrap();
+        let map = cache.get_mmap("data0").unwrap().unwrap();
+        assert_eq!(&*map, b"abc");
+
+        // The map keeps the replaced value.
+        cache.insert("data0", &"def".to_owned()).unwrap();
+        assert_eq!(&*map, b"abc");
+        assert_eq!(&*cache.get_mmap("data0").unwrap().unwrap(), b"def");
+
+        cache.or_insert_with_stream("data1", |w| w.write_all(b"streamed")).unwrap();
+        assert_eq!(&*cache.get_mmap("data1").unwrap().unwrap(), b"streamed");
+        assert!(cache.get_mmap("data2").unwrap().is_none());
     }
 
     fn read_all_bytes<P: AsRef<Path>>(path: P) -> Vec<u8> {
@@ -153,14 +1479,14 @@ mod tests {
         let mut save_path = dir.path().to_owned();
         save_path.push("test.save");
 
-        LocalFileCache::<()>::save_to(&path, &[12u8]).unwrap();
+        LocalFileCache::<()>::save_to(&path, &[12u8], Durability::None).unwrap();
         assert_eq!(read_all_bytes(&path), vec![12u8]);
 
-        LocalFileCache::<()>::save_to(&path, &[23u8]).unwrap();
+        LocalFileCache::<()>::save_to(&path, &[23u8], Durability::None).unwrap();
         assert_eq!(read_all_bytes(&path), vec![23u8]);
 
-        LocalFileCache::<()>::save_to(&save_path, &[123u8]).unwrap();
-        LocalFileCache::<()>::save_to(&path, &[34u8]).unwrap();
+        LocalFileCache::<()>::save_to(&save_path, &[123u8], Durability::None).unwrap();
+        LocalFileCache::<()>::save_to(&path, &[34u8], Durability::None).unwrap();
         assert_eq!(read_all_bytes(&path), vec![23u8]);
     }
 }
@@ -0,0 +1,211 @@
+// Bounded in-memory LRU of decoded values in front of the entry files.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::entry;
+
+// Hits served from memory are recorded in the entry file at most this often, and when the value is dropped from memory.
+const HIT_RECORD_INTERVAL: Duration = Duration::from_secs(1);
+
+// Returns the weight of a value held in memory, e.g. its approximate size in bytes.
+pub(crate) type Weigher<T> = Box<dyn Fn(&T) -> usize + Send + Sync>;
+
+pub(crate) struct MemoryTier<T> {
+    max_entries: Option<usize>,
+    max_weight: Option<(usize, Weigher<T>)>,
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    slots: HashMap<PathBuf, Slot<T>>,
+    // Last use of every slot, oldest first.
+    order: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    weight: usize,
+    // Bumped whenever entries are invalidated.
+    generation: u64,
+}
+
+struct Slot<T> {
+    value: Arc<T>,
+    expires_at: Option<SystemTime>,
+    weight: usize,
+    tick: u64,
+    // Hits not yet recorded in the entry file.
+    hits: u64,
+    last_hit: SystemTime,
+    recorded_at: Instant,
+}
+
+impl<T> MemoryTier<T> {
+    pub fn new() -> Self {
+        Self {
+            max_entries: None,
+            max_weight: None,
+            state: Mutex::new(State {
+                slots: HashMap::new(), order: BTreeMap::new(), tick: 0, weight: 0, generation: 0,
+            }),
+        }
+    }
+
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = Some(max_entries);
+    }
+
+    pub fn set_max_weight(&mut self, max_weight: usize, weigher: Weigher<T>) {
+        self.max_weight = Some((max_weight, weigher));
+    }
+
+    pub fn get(&self, path: &Path, now: SystemTime) -> Option<Arc<T>> {
+        let mut state = self.state();
+        let slot = state.slots.get(path)?;
+        if slot.expires_at.is_some_and(|t| t <= now) {
+            state.remove(path);
+            return None;
+        }
+        let last = slot.tick;
+        let tick = state.next_tick();
+        state.order.remove(&last);
+        state.order.insert(tick, path.to_owned());
+        let slot = state.slots.get_mut(path)?;
+        slot.tick = tick;
+        slot.hits += 1;
+        slot.last_hit = now;
+        let due = HIT_RECORD_INTERVAL <= slot.recorded_at.elapsed();
+        let (value, hits) = (slot.value.clone(), slot.hits);
+        if due {
+            slot.hits = 0;
+            slot.recorded_at = Instant::now();
+        }
+        drop(state);
+        if due {
+            record_hits(path, now, hits);
+        }
+        Some(value)
+    }
+
+    // Read before loading a value from disk and passed to insert, so that a value loaded before a concurrent
+    // invalidation is not kept.
+    pub fn generation(&self) -> u64 {
+        self.state().generation
+    }
+
+    pub fn insert(&self, path: &Path, value: Arc<T>, expires_at: Option<SystemTime>, generation: u64) {
+        let weight = self.max_weight.as_ref().map_or(0, |(_, weigher)| weigher(&value));
+        let mut state = self.state();
+        if state.generation != generation || self.max_weight.as_ref().is_some_and(|(max, _)| *max < weight) {
+            return;
+        }
+        state.remove(path);
+        let tick = state.next_tick();
+        state.order.insert(tick, path.to_owned());
+        let slot = Slot {
+            value, expires_at, weight, tick, hits: 0, last_hit: SystemTime::UNIX_EPOCH, recorded_at: Instant::now(),
+        };
+        state.slots.insert(path.to_owned(), slot);
+        state.weight += weight;
+
+        let over = |state: &State<T>| {
+            self.max_entries.is_some_and(|max| max < state.slots.len())
+                || self.max_weight.as_ref().is_some_and(|(max, _)| *max < state.weight)
+        };
+        let mut dropped = Vec::new();
+        while over(&state) {
+            let Some((_, oldest)) = state.order.pop_first() else { break };
+            if let Some(slot) = state.slots.remove(&oldest) {
+                state.weight -= slot.weight;
+                dropped.push((oldest, slot));
+            }
+        }
+        drop(state);
+        for (path, slot) in dropped {
+            record_hits(&path, slot.last_hit, slot.hits);
+        }
+    }
+
+    pub fn remove(&self, path: &Path) {
+        let mut state = self.state();
+        state.generation += 1;
+        state.remove(path);
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.generation += 1;
+        state.slots.clear();
+        state.order.clear();
+        state.weight = 0;
+    }
+
+    // Values are only replaced as a whole, so poisoning is ignored.
+    fn state(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Drop for MemoryTier<T> {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for (path, slot) in &state.slots {
+            record_hits(path, slot.last_hit, slot.hits);
+        }
+    }
+}
+
+// Failing to record hits only affects eviction order.
+fn record_hits(path: &Path, last_hit: SystemTime, hits: u64) {
+    if hits != 0 {
+        let _ = entry::record_hits(path, last_hit, hits);
+    }
+}
+
+impl<T> State<T> {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(slot) = self.slots.remove(path) {
+            self.order.remove(&slot.tick);
+            self.weight -= slot.weight;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn least_recently_used_values_are_dropped() {
+        let mut tier = MemoryTier::<String>::new();
+        tier.set_max_entries(2);
+        tier.set_max_weight(8, Box::new(|s| s.len()));
+        let now = SystemTime::now();
+        let insert = |k: &str, v: &str| tier.insert(Path::new(k), Arc::new(v.to_owned()), None, tier.generation());
+
+        insert("a", "aaa");
+        insert("b", "bbb");
+        assert!(tier.get(Path::new("a"), now).is_some());
+        insert("c", "ccc");
+        assert!(tier.get(Path::new("b"), now).is_none());
+        insert("d", "dddddd");
+        assert!(tier.get(Path::new("a"), now).is_none());
+        assert_eq!(*tier.get(Path::new("d"), now).unwrap(), "dddddd");
+        insert("e", "too heavy");
+        assert!(tier.get(Path::new("e"), now).is_none());
+
+        let stale = tier.generation();
+        tier.remove(Path::new("d"));
+        tier.insert(Path::new("d"), Arc::new("old".to_owned()), None, stale);
+        assert!(tier.get(Path::new("d"), now).is_none());
+
+        tier.insert(Path::new("f"), Arc::new("f".to_owned()), Some(now), tier.generation());
+        assert!(tier.get(Path::new("f"), now + Duration::from_millis(1)).is_none());
+    }
+}
@@ -0,0 +1,39 @@
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+
+use memmap2::{Mmap, MmapOptions};
+
+/// Read-only memory map over the payload of an entry, see [`LocalFileCache::get_mmap`](crate::LocalFileCache::get_mmap).
+///
+/// Replacing or removing the entry does not affect an existing map because new entries are renamed into place as
+/// new files. Truncating the entry file by other means while it is mapped may crash the process.
+#[derive(Debug)]
+pub struct EntryMap {
+    map: Mmap,
+}
+
+impl EntryMap {
+    pub(crate) fn new(file: &File, start: u64, len: u64) -> io::Result<Self> {
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "entry is too large to map"))?;
+        // SAFETY: Entry files are only modified in place in their header, which is outside the mapped range.
+        // New values are written to another file that is renamed over the entry, so the mapped file stays unchanged.
+        let map = unsafe { MmapOptions::new().offset(start).len(len).map(file)? };
+        Ok(Self { map })
+    }
+}
+
+impl Deref for EntryMap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl AsRef<[u8]> for EntryMap {
+    fn as_ref(&self) -> &[u8] {
+        &self.map
+    }
+}
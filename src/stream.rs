@@ -0,0 +1,199 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::checksum::{Hasher, CHECKSUM_LEN};
+use crate::flight;
+
+/// Reads the payload of an entry written by
+/// [`LocalFileCache::or_insert_with_stream`](crate::LocalFileCache::or_insert_with_stream).
+///
+/// Positions are relative to the start of the payload. The reader keeps the entry file open, so it stays readable
+/// even if the entry is removed or replaced meanwhile.
+///
+/// A reader returned for an existing entry verifies its checksum when the payload is read to the end without seeking.
+/// On a mismatch the last read fails with [`io::ErrorKind::InvalidData`] and the entry is removed.
+pub struct EntryReader {
+    file: File,
+    start: u64,
+    len: u64,
+    pos: u64,
+    verify: Option<Verify>,
+}
+
+// The checksum of a payload being read.
+struct Verify {
+    hasher: Hasher,
+    expected: [u8; CHECKSUM_LEN],
+    path: PathBuf,
+}
+
+impl EntryReader {
+    // The file must be positioned at start.
+    pub(crate) fn new(file: File, start: u64, len: u64) -> Self {
+        Self { file, start, len, pos: 0, verify: None }
+    }
+
+    // Verifies the payload against the checksum while it is read. The entry at path is removed if it does not match.
+    pub(crate) fn verified(mut self, hasher: Hasher, expected: [u8; CHECKSUM_LEN], path: PathBuf) -> Self {
+        self.verify = Some(Verify { hasher, expected, path });
+        self
+    }
+
+    /// Length of the payload in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let max = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let n = self.file.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        if let Some(verify) = &mut self.verify {
+            verify.hasher.update(&buf[..n]);
+            // A file truncated meanwhile ends early.
+            if self.pos == self.len || (n == 0 && max != 0) {
+                self.verify.take().map_or(Ok(()), |verify| verify.finish(&self.file))?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Verify {
+    fn finish(self, file: &File) -> io::Result<()> {
+        if self.hasher.finish() == self.expected {
+            return Ok(());
+        }
+        // The entry may have been replaced by a new one meanwhile.
+        if flight::is_current(file, &self.path)? {
+            let _ = fs::remove_file(&self.path);
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, "checksum mismatch in cache entry"))
+    }
+}
+
+impl std::fmt::Debug for EntryReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EntryReader").field("len", &self.len).field("pos", &self.pos).finish_non_exhaustive()
+    }
+}
+
+impl Seek for EntryReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => (0, n as i128),
+            SeekFrom::End(n) => (self.len, n as i128),
+            SeekFrom::Current(n) => (self.pos, n as i128),
+        };
+        let pos = u64::try_from(base as i128 + offset)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the entry"))?;
+        self.file.seek(SeekFrom::Start(self.start + pos))?;
+        // Only reads from the start to the end are verified.
+        if pos != self.pos {
+            self.verify = None;
+        }
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+// Writes the payload of a streamed entry while computing its checksum.
+pub(crate) struct HashingWriter<'a> {
+    inner: BufWriter<&'a mut File>,
+    hasher: Hasher,
+}
+
+impl<'a> HashingWriter<'a> {
+    pub fn new(file: &'a mut File, hasher: Hasher) -> Self {
+        Self { inner: BufWriter::new(file), hasher }
+    }
+
+    // Flushes the payload and returns the hasher.
+    pub fn finish(mut self) -> io::Result<Hasher> {
+        self.inner.flush()?;
+        Ok(self.hasher)
+    }
+}
+
+impl Write for HashingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Feeds everything written to the hasher.
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Checksum;
+
+    #[test]
+    fn reader_is_limited_to_the_payload() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"headerpayload").unwrap();
+        file.seek(SeekFrom::Start(6)).unwrap();
+        let mut reader = EntryReader::new(file, 6, 4);
+
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "payl");
+
+        assert_eq!(reader.seek(SeekFrom::End(-2)).unwrap(), 2);
+        buf.clear();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "yl");
+        assert!(reader.seek(SeekFrom::Current(-5)).is_err());
+    }
+
+    #[test]
+    fn corrupt_payload_fails_at_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("entry");
+        std::fs::write(&path, b"headerpayload").unwrap();
+        let open = |checksum: Checksum| {
+            let mut file = File::open(&path).unwrap();
+            file.seek(SeekFrom::Start(6)).unwrap();
+            EntryReader::new(file, 6, 7).verified(Checksum::Crc32.hasher(), checksum.compute(b"payload"), path.clone())
+        };
+
+        let mut buf = Vec::new();
+        open(Checksum::Crc32).read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"payload");
+
+        // Not verified after seeking.
+        let mut reader = open(Checksum::Sha256);
+        reader.seek(SeekFrom::Start(1)).unwrap();
+        reader.read_to_end(&mut buf).unwrap();
+
+        let mut reader = open(Checksum::Sha256);
+        reader.read_exact(&mut [0; 3]).unwrap();
+        let err = reader.read_to_end(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!path.exists());
+    }
+}